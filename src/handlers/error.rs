@@ -0,0 +1,165 @@
+use actix_web::{
+    error::{PathError, QueryPayloadError},
+    http::StatusCode,
+    HttpRequest, HttpResponse, ResponseError,
+};
+use nostr_rust::nips::nip5::NIP5Error;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::systems::{
+    cache::CacheError,
+    fetcher::FetchError,
+    image_cache::{ImageCacheError, InfoError},
+    og_extractor::OgExtractorError,
+};
+
+/// Error returned by the handlers, rendered as
+/// `{ "status": "error", "code": "<machine readable code>", "message": "<details>" }`
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error(transparent)]
+    ImageCache(#[from] ImageCacheError),
+
+    #[error(transparent)]
+    Info(#[from] InfoError),
+
+    #[error(transparent)]
+    OgExtractor(#[from] OgExtractorError),
+
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+
+    #[error(transparent)]
+    Nip05(#[from] NIP5Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    // Query parameters that can't be deserialized: missing, unknown type or out of range
+    #[error("Invalid query: {0}")]
+    Query(#[from] QueryPayloadError),
+
+    #[error("Invalid path: {0}")]
+    Path(#[from] PathError),
+}
+
+impl ApiError {
+    // HTTP status and machine readable code of the error
+    fn kind(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::ImageCache(err) => match err {
+                ImageCacheError::WidthTooLarge
+                | ImageCacheError::HeightTooLarge
+                | ImageCacheError::SizeTooLargeAfterRatio => {
+                    (StatusCode::BAD_REQUEST, "size_too_large")
+                }
+                ImageCacheError::InfoError(err) => info_error_kind(err),
+                ImageCacheError::FetchError(err) => fetch_error_kind(err),
+                ImageCacheError::UnsupportedFormat => {
+                    (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_format")
+                }
+                ImageCacheError::SourceTooLarge(..) | ImageCacheError::TooManyFrames(_) => {
+                    (StatusCode::PAYLOAD_TOO_LARGE, "image_too_large")
+                }
+                ImageCacheError::ImageError(image::ImageError::Limits(_)) => {
+                    (StatusCode::PAYLOAD_TOO_LARGE, "image_too_large")
+                }
+                ImageCacheError::ImageError(image::ImageError::Encoding(_)) => {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "encoding_error")
+                }
+                ImageCacheError::ImageError(_) => {
+                    (StatusCode::UNSUPPORTED_MEDIA_TYPE, "decoding_error")
+                }
+                ImageCacheError::TaskFailed(_) => {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+                }
+            },
+            ApiError::Info(err) => info_error_kind(err),
+            ApiError::OgExtractor(OgExtractorError::FetchError(err)) => fetch_error_kind(err),
+            ApiError::Cache(CacheError::Timeout) => (StatusCode::GATEWAY_TIMEOUT, "cache_timeout"),
+            ApiError::Cache(_) => (StatusCode::BAD_GATEWAY, "cache_error"),
+            ApiError::Fetch(err) => fetch_error_kind(err),
+            ApiError::Nip05(err) => match err {
+                NIP5Error::InvalidFormat => (StatusCode::BAD_REQUEST, "invalid_nip05"),
+                NIP5Error::MatchFailed => (StatusCode::NOT_FOUND, "nip05_not_found"),
+                NIP5Error::InvalidResponseFormat | NIP5Error::RequestFailed => {
+                    (StatusCode::BAD_GATEWAY, "invalid_nip05_response")
+                }
+            },
+            ApiError::Serialization(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            ApiError::Query(_) => (StatusCode::BAD_REQUEST, "invalid_query"),
+            ApiError::Path(_) => (StatusCode::BAD_REQUEST, "invalid_path"),
+        }
+    }
+}
+
+fn info_error_kind(err: &InfoError) -> (StatusCode, &'static str) {
+    match err {
+        InfoError::InvalidRatioFormat => (StatusCode::BAD_REQUEST, "invalid_ratio"),
+        InfoError::SizeTooSmall => (StatusCode::BAD_REQUEST, "invalid_size"),
+        InfoError::InvalidFormat => (StatusCode::BAD_REQUEST, "invalid_format"),
+        InfoError::InvalidQuality => (StatusCode::BAD_REQUEST, "invalid_quality"),
+        InfoError::InvalidCompression => (StatusCode::BAD_REQUEST, "invalid_compression"),
+        InfoError::InvalidPalette => (StatusCode::BAD_REQUEST, "invalid_palette"),
+        InfoError::InvalidFit => (StatusCode::BAD_REQUEST, "invalid_fit"),
+        InfoError::InvalidFilter => (StatusCode::BAD_REQUEST, "invalid_filter"),
+        InfoError::InvalidBackground => (StatusCode::BAD_REQUEST, "invalid_background"),
+        InfoError::InvalidGravity => (StatusCode::BAD_REQUEST, "invalid_gravity"),
+        InfoError::InvalidFocus => (StatusCode::BAD_REQUEST, "invalid_focus"),
+        InfoError::InvalidFrame => (StatusCode::BAD_REQUEST, "invalid_frame"),
+        InfoError::InvalidPlaceholder => (StatusCode::BAD_REQUEST, "invalid_placeholder"),
+        InfoError::InvalidPreset => (StatusCode::BAD_REQUEST, "invalid_preset"),
+        InfoError::PresetWithSize => (StatusCode::BAD_REQUEST, "preset_with_size"),
+        InfoError::CustomSizeForbidden => (StatusCode::BAD_REQUEST, "custom_size_forbidden"),
+        InfoError::InvalidSource => (StatusCode::BAD_REQUEST, "invalid_source"),
+        InfoError::InvalidOptions => (StatusCode::BAD_REQUEST, "invalid_options"),
+    }
+}
+
+fn fetch_error_kind(err: &FetchError) -> (StatusCode, &'static str) {
+    match err {
+        FetchError::InvalidUrl(_) => (StatusCode::BAD_REQUEST, "invalid_url"),
+        FetchError::ForbiddenUrl(_) => (StatusCode::FORBIDDEN, "forbidden_url"),
+        FetchError::ReqwestError(err) => reqwest_error_kind(err),
+        FetchError::UpstreamStatus(_) => (StatusCode::BAD_GATEWAY, "upstream_status"),
+        FetchError::TooManyRedirects => (StatusCode::BAD_GATEWAY, "too_many_redirects"),
+        FetchError::TooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "source_too_large"),
+        FetchError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout"),
+    }
+}
+
+fn reqwest_error_kind(err: &reqwest::Error) -> (StatusCode, &'static str) {
+    if err.is_timeout() {
+        (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout")
+    } else {
+        (StatusCode::BAD_GATEWAY, "upstream_error")
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.kind().0
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "status": "error",
+            "code": self.kind().1,
+            "message": self.to_string(),
+        }))
+    }
+}
+
+/// Error handler of `web::QueryConfig`, so that the extraction errors have the same body as the others
+pub fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::from(err).into()
+}
+
+/// Error handler of `web::PathConfig`
+pub fn path_error_handler(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::from(err).into()
+}
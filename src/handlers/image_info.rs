@@ -0,0 +1,19 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    handlers::error::ApiError,
+    systems::image_cache::{self, MetadataInfo},
+    WebStates,
+};
+
+pub async fn get(
+    info: web::Query<MetadataInfo>,
+    data: web::Data<WebStates>,
+) -> Result<HttpResponse, ApiError> {
+    let image_info =
+        image_cache::cache_image_info(&info, &data.cache.to_owned(), &data.fetcher).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(image_info))
+}
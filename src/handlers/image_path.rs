@@ -0,0 +1,112 @@
+use actix_web::{web, HttpResponse};
+use base64::Engine;
+
+use crate::{
+    handlers::error::ApiError,
+    systems::image_cache::{self, Info, InfoError},
+    WebStates,
+};
+
+/// Path-style `/image_proxy`: `/i/<options>/<base64url(source)>.<ext>`
+///
+/// The options are the query parameters of `/image_proxy` separated by `&`, or `_` for none.
+/// The extension sets the output format, so the response never depends on the Accept header.
+pub async fn get(
+    path: web::Path<(String, String)>,
+    data: web::Data<WebStates>,
+) -> Result<HttpResponse, ApiError> {
+    let (options, source) = path.into_inner();
+    let info = path_info(&options, &source)?;
+
+    let (cache_content, cache_mime_type) =
+        image_cache::cache_image(&info, None, &data.cache, &data.media_cache, &data.fetcher)
+            .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(cache_mime_type)
+        .body(cache_content))
+}
+
+fn path_info(options: &str, source: &str) -> Result<Info, InfoError> {
+    let (source, extension) = source.rsplit_once('.').ok_or(InfoError::InvalidSource)?;
+
+    // Padding is optional
+    let url = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(source.trim_end_matches('='))
+        .ok()
+        .and_then(|url| String::from_utf8(url).ok())
+        .ok_or(InfoError::InvalidSource)?;
+
+    let options = match options {
+        "_" => "",
+        options => options,
+    };
+
+    // A url or a format in the options is a duplicate field
+    let query = form_urlencoded::Serializer::new(options.to_string())
+        .append_pair("url", &url)
+        .append_pair("format", extension)
+        .finish();
+
+    web::Query::<Info>::from_query(&query)
+        .map(web::Query::into_inner)
+        .map_err(|_| InfoError::InvalidOptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_source() {
+        // "https://example.com/?q=>>>" uses both characters of the base64url alphabet
+        for source in [
+            "aHR0cHM6Ly9leGFtcGxlLmNvbS8_cT0-Pj4=.png",
+            "aHR0cHM6Ly9leGFtcGxlLmNvbS8_cT0-Pj4.png",
+        ] {
+            let info = path_info("_", source).unwrap();
+            assert_eq!(info.url, "https://example.com/?q=>>>");
+            assert_eq!(info.format.as_deref(), Some("png"));
+        }
+
+        // The standard alphabet is refused
+        assert_eq!(
+            path_info("_", "aHR0cHM6Ly9leGFtcGxlLmNvbS8/cT0+Pj4.png").err(),
+            Some(InfoError::InvalidSource)
+        );
+    }
+
+    #[test]
+    fn reads_the_options() {
+        let info = path_info(
+            "width=800&ratio=16:9",
+            "aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.gif",
+        )
+        .unwrap();
+        assert_eq!(info.url, "https://example.com/image.png");
+        assert_eq!(info.width, Some(800.0));
+        assert_eq!(info.ratio.as_deref(), Some("16:9"));
+        assert_eq!(info.format.as_deref(), Some("gif"));
+    }
+
+    #[test]
+    fn refuses_invalid_paths() {
+        // Without extension
+        assert_eq!(
+            path_info("_", "aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc").err(),
+            Some(InfoError::InvalidSource)
+        );
+        // Not base64
+        assert_eq!(
+            path_info("_", "not base64!.png").err(),
+            Some(InfoError::InvalidSource)
+        );
+        // The url and the format can only be set by the path
+        for options in ["url=https://example.com/other.png", "format=jpeg"] {
+            assert_eq!(
+                path_info(options, "aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.png").err(),
+                Some(InfoError::InvalidOptions)
+            );
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    handlers::error::ApiError,
+    systems::image_cache::{self, PlaceholderInfo},
+    WebStates,
+};
+
+pub async fn get(
+    info: web::Query<PlaceholderInfo>,
+    data: web::Data<WebStates>,
+) -> Result<HttpResponse, ApiError> {
+    let placeholder =
+        image_cache::cache_placeholder(&info, &data.cache.to_owned(), &data.fetcher).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(placeholder))
+}
@@ -1,27 +1,28 @@
-use actix_web::{web, HttpResponse, Responder};
-use serde::Deserialize;
-use thiserror::Error;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
 
 use crate::{
+    handlers::error::ApiError,
     systems::image_cache::{self, Info},
     WebStates,
 };
 
-#[derive(Debug, Deserialize, Error)]
-pub enum InfoError {
-    #[error("Invalid ratio format")]
-    InvalidRatioFormat,
+pub async fn get(
+    req: HttpRequest,
+    info: web::Query<Info>,
+    data: web::Data<WebStates>,
+) -> Result<HttpResponse, ApiError> {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
 
-    #[error("No width, height, or ratio defined")]
-    NoSizeDefined,
-}
-
-pub async fn get(info: web::Query<Info>, data: web::Data<WebStates>) -> impl Responder {
-    let (cache_content, cache_mime_type) = image_cache::cache_image(&info, &data.cache.to_owned())
-        .await
-        .unwrap();
+    let (cache_content, cache_mime_type) =
+        image_cache::cache_image(&info, accept, &data.cache, &data.media_cache, &data.fetcher)
+            .await?;
 
-    HttpResponse::Ok()
+    // The output format depends on the Accept header
+    Ok(HttpResponse::Ok()
         .content_type(cache_mime_type)
-        .body(cache_content)
+        .insert_header((header::VARY, "Accept"))
+        .body(cache_content))
 }
@@ -1,5 +1,32 @@
+pub mod error;
+pub mod image_info;
+pub mod image_path;
+pub mod image_placeholder;
 pub mod image_proxy;
 pub mod index;
 pub mod nip05;
+pub mod stats;
 pub mod verify;
 pub mod website_previews;
+
+#[cfg(test)]
+pub mod tests {
+    use actix_web::web;
+
+    use crate::{
+        systems::{
+            cache::{tests::ram_cache, CacheMediaStore, MediaCache},
+            fetcher::{tests::policy, Fetcher},
+        },
+        WebStates,
+    };
+
+    /// States with in-memory caches, the fetcher refuses the private addresses
+    pub fn web_states() -> web::Data<WebStates> {
+        web::Data::new(WebStates {
+            cache: ram_cache(),
+            media_cache: MediaCache::new(CacheMediaStore::new(ram_cache())),
+            fetcher: Fetcher::new(policy()).unwrap(),
+        })
+    }
+}
@@ -1,15 +1,20 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse};
+use nostr_rust::nips::nip5::{NIP5Error, NostrWellKnown};
+use reqwest::Url;
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::WebStates;
+use crate::{handlers::error::ApiError, systems::fetcher::Fetcher, WebStates};
 
 #[derive(Deserialize)]
 pub struct Info {
     nip05: String,
 }
 
-pub async fn get(info: web::Query<Info>, data: web::Data<WebStates>) -> impl Responder {
+pub async fn get(
+    info: web::Query<Info>,
+    data: web::Data<WebStates>,
+) -> Result<HttpResponse, ApiError> {
     println!("NIP05: {}", info.nip05);
 
     let cache_key = format!("nip05:{}", info.nip05);
@@ -17,28 +22,22 @@ pub async fn get(info: web::Query<Info>, data: web::Data<WebStates>) -> impl Res
     let cache_response = data.cache.to_owned().get_str(&cache_key).await;
 
     if let Ok(cache_response) = cache_response {
-        return HttpResponse::Ok().body(cache_response);
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json; charset=utf-8")
+            .body(cache_response));
     }
 
-    let nip05_response = nostr_rust::nips::nip5::get_nip05(&info.nip05).await;
-
-    let body_response = match nip05_response {
-        Ok(response) => {
-            json!({
-                "status": "success",
-                "pubkey": response,
-                "updated_at": chrono::Utc::now().timestamp()
-            })
-        }
-        Err(err) => {
-            json!({
-                "status": "error",
-                "message": err.to_string()
-            })
-        }
-    };
-
-    data.cache
+    // Only the successes are cached
+    let pubkey = get_nip05(&data.fetcher, &info.nip05).await?;
+
+    let body_response = json!({
+        "status": "success",
+        "pubkey": pubkey,
+        "updated_at": chrono::Utc::now().timestamp()
+    });
+
+    if let Err(err) = data
+        .cache
         .to_owned()
         .set_str(
             &cache_key,
@@ -46,7 +45,80 @@ pub async fn get(info: web::Query<Info>, data: web::Data<WebStates>) -> impl Res
             crate::ENV_CONFIG.cache_ttl_nip05.to_owned(),
         )
         .await
-        .unwrap();
+    {
+        println!("Cache error: {err}");
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(body_response.to_string()))
+}
+
+/// Public key of a `name@domain` identifier, fetched with the same policy as the images
+async fn get_nip05(fetcher: &Fetcher, nip05: &str) -> Result<String, ApiError> {
+    let (name, domain) = nip05.split_once('@').ok_or(NIP5Error::InvalidFormat)?;
+    if name.is_empty() || domain.is_empty() || domain.contains('@') {
+        return Err(NIP5Error::InvalidFormat.into());
+    }
+
+    // The domain must not carry its own path, query or fragment
+    let mut url = Url::parse(&format!("https://{domain}/.well-known/nostr.json"))
+        .map_err(|_| NIP5Error::InvalidFormat)?;
+    if url.path() != "/.well-known/nostr.json" || url.query().is_some() || url.fragment().is_some()
+    {
+        return Err(NIP5Error::InvalidFormat.into());
+    }
+    url.query_pairs_mut().append_pair("name", name);
+
+    let body = fetcher.get_bytes(url.as_str()).await?;
+    let well_known: NostrWellKnown =
+        serde_json::from_slice(&body).map_err(|_| NIP5Error::InvalidResponseFormat)?;
+
+    well_known
+        .names
+        .get(name)
+        .cloned()
+        .ok_or_else(|| NIP5Error::MatchFailed.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::tests::web_states;
+    use actix_web::{body::MessageBody, http::StatusCode, ResponseError};
+
+    fn query(nip05: &str) -> web::Query<Info> {
+        web::Query(Info {
+            nip05: nip05.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn serves_the_cached_response() {
+        let data = web_states();
+        let cached = r#"{"status":"success","pubkey":"abc","updated_at":0}"#;
+        data.cache
+            .set_str("nip05:_@example.com", cached, 60)
+            .await
+            .unwrap();
 
-    HttpResponse::Ok().body(body_response.to_string())
+        let response = get(query("_@example.com"), data).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.into_body().try_into_bytes().unwrap(), cached);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_the_failures() {
+        let data = web_states();
+
+        let err = get(query("invalid"), data.clone()).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        // Refused by the fetcher before any connection
+        let err = get(query("_@127.0.0.1"), data.clone()).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+        assert!(data.cache.get_str("nip05:invalid").await.is_err());
+        assert!(data.cache.get_str("nip05:_@127.0.0.1").await.is_err());
+    }
 }
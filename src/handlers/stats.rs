@@ -0,0 +1,8 @@
+use actix_web::{HttpResponse, Responder};
+use serde_json::json;
+
+pub async fn get() -> impl Responder {
+    let ram_cache = crate::RAM_CACHE.lock().await.stats();
+
+    HttpResponse::Ok().json(json!({ "ram_cache": ram_cache }))
+}
@@ -1,33 +1,80 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse};
 use serde::Deserialize;
 
+use crate::handlers::error::ApiError;
+
 #[derive(Deserialize)]
 pub struct Info {
     url: String,
 }
 
-pub async fn get(info: web::Query<Info>, data: web::Data<crate::WebStates>) -> impl Responder {
+pub async fn get(
+    info: web::Query<Info>,
+    data: web::Data<crate::WebStates>,
+) -> Result<HttpResponse, ApiError> {
     let cache_key = format!("og:{}", info.url);
 
     let og = match data.cache.to_owned().get_str(&cache_key).await {
         Ok(og) => og,
         Err(_) => {
-            let og = crate::systems::og_extractor::og_extractor(&info.url)
-                .await
-                .unwrap();
+            let og = crate::systems::og_extractor::og_extractor(&info.url, &data.fetcher).await?;
 
-            let og_str = serde_json::to_string(&og).unwrap();
+            let og_str = serde_json::to_string(&og)?;
 
-            data.cache
+            if let Err(err) = data
+                .cache
                 .to_owned()
                 .set_str(&cache_key, &og_str, crate::ENV_CONFIG.cache_ttl_webpreview)
                 .await
-                .unwrap();
+            {
+                println!("Cache error: {err}");
+            }
             og_str
         }
     };
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type("application/json; charset=utf-8")
-        .body(og)
+        .body(og))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::tests::web_states;
+    use actix_web::{body::MessageBody, http::StatusCode, ResponseError};
+
+    #[tokio::test]
+    async fn serves_the_cached_preview() {
+        let data = web_states();
+        let cached = r#"{"title":"Example"}"#;
+        data.cache
+            .set_str("og:https://example.com", cached, 60)
+            .await
+            .unwrap();
+
+        let response = get(
+            web::Query(Info {
+                url: "https://example.com".to_string(),
+            }),
+            data,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.into_body().try_into_bytes().unwrap(), cached);
+    }
+
+    #[tokio::test]
+    async fn refuses_forbidden_urls() {
+        let err = get(
+            web::Query(Info {
+                url: "http://169.254.169.254/latest/meta-data".to_string(),
+            }),
+            web_states(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+    }
 }
@@ -2,7 +2,7 @@ use actix_cors::Cors;
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use async_lock::Mutex;
 use lazy_static::lazy_static;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use strum::EnumString;
 use systems::cache;
 
@@ -17,11 +17,15 @@ pub enum MediaCacheType {
     #[strum(ascii_case_insensitive)]
     RAM,
     #[strum(ascii_case_insensitive)]
+    Disk { path: String, max_bytes: u64 },
+    #[strum(ascii_case_insensitive)]
     S3 {
+        endpoint: String,
         bucket: String,
         region: String,
         access_key: String,
         secret_key: String,
+        timeout_ms: u64,
     },
 }
 
@@ -42,32 +46,87 @@ pub enum DynamicCacheType {
     REDIS,
     #[strum(ascii_case_insensitive)]
     RAM,
+    // RAM in front of Redis
+    #[strum(ascii_case_insensitive)]
+    TIERED,
 }
 
 pub struct EnvConfig {
-    // DYNAMIC_CACHE_TYPE = "redis" | "ram"
+    // DYNAMIC_CACHE_TYPE = "redis" | "ram" | "tiered"
     pub dynamic_cache_type: DynamicCacheType,
     // DYNAMIC_CACHE_GC_INTERVAL
     pub dynamic_cache_gc_interval: usize,
     pub redis_url: Option<String>,
+    // REDIS_TIMEOUT_MS
+    pub redis_timeout_ms: u64,
+    // TIERED_RAM_TTL
+    pub tiered_ram_ttl: usize,
     // RAM_LIMIT_OBJECTS
     pub ram_limit_objects: usize,
-    // IMAGES_CACHE_TYPE
+    // RAM_LIMIT_BYTES
+    pub ram_limit_bytes: usize,
+    // IMAGES_CACHE_TYPE (+ DISK_CACHE_* or S3_BUCKET_* depending on the type)
     pub images_cache_type: MediaCacheType,
     // IMAGE_MAX_WIDTH
     pub image_max_width: usize,
     // IMAGE_MAX_HEIGHT
     pub image_max_height: usize,
+    // IMAGE_DEFAULT_QUALITY
+    pub image_default_quality: u8,
+    // IMAGE_MIN_QUALITY
+    pub image_min_quality: u8,
+    // IMAGE_MAX_QUALITY
+    pub image_max_quality: u8,
+    // IMAGE_MAX_SOURCE_WIDTH
+    pub image_max_source_width: u32,
+    // IMAGE_MAX_SOURCE_HEIGHT
+    pub image_max_source_height: u32,
+    // IMAGE_MAX_SOURCE_PIXELS
+    pub image_max_source_pixels: u64,
+    // IMAGE_MAX_SOURCE_FRAMES
+    pub image_max_source_frames: usize,
+    // IMAGE_MAX_DECODE_BYTES
+    pub image_max_decode_bytes: u64,
+    // IMAGE_PRESETS = "name:parameters;name:parameters"
+    pub image_presets: HashMap<String, systems::image_cache::Preset>,
+    // IMAGE_CUSTOM_SIZES
+    pub image_custom_sizes: bool,
+    // IMAGE_WIDTH_LADDER (sorted)
+    pub image_width_ladder: Vec<u32>,
     // RESTRICTED_PUBKEYS
     pub restricted_pubkeys: Vec<String>,
     // PASSWORD
     pub password: Option<String>,
+    // IMAGE_SIGNING_KEY
+    pub image_signing_key: Option<String>,
+    // IMAGE_REQUIRE_SIGNATURE
+    pub image_require_signature: bool,
     // RESTRICTED_IMAGES
     pub restricted_images: Vec<RestrictedImages>,
+    // FETCH_ALLOWED_HOSTS
+    pub fetch_allowed_hosts: Vec<String>,
+    // FETCH_DENIED_HOSTS
+    pub fetch_denied_hosts: Vec<String>,
+    // FETCH_ALLOWED_PORTS
+    pub fetch_allowed_ports: Vec<u16>,
+    // FETCH_ALLOW_PRIVATE_IPS
+    pub fetch_allow_private_ips: bool,
+    // FETCH_MAX_BODY_BYTES
+    pub fetch_max_body_bytes: usize,
+    // FETCH_MAX_REDIRECTS
+    pub fetch_max_redirects: usize,
+    // FETCH_CONNECT_TIMEOUT_MS
+    pub fetch_connect_timeout_ms: u64,
+    // FETCH_READ_TIMEOUT_MS
+    pub fetch_read_timeout_ms: u64,
+    // FETCH_TIMEOUT_MS
+    pub fetch_timeout_ms: u64,
     // CACHE_TTL_NIP05
     pub cache_ttl_nip05: usize,
     // CACHE_TTL_IMAGES
     pub cache_ttl_images: usize,
+    // CACHE_TTL_IMAGE_INFO
+    pub cache_ttl_image_info: usize,
     // CACHE_TTL_WEBPREVIEW
     pub cache_ttl_webpreview: usize,
     // CACHE_TTL_SIGNATURE
@@ -75,26 +134,65 @@ pub struct EnvConfig {
 }
 
 lazy_static! {
-    static ref RAM_CACHE: Arc<Mutex<systems::ram_cache::RamCache>> =
-        Arc::new(Mutex::new(systems::ram_cache::RamCache::new()));
+    static ref RAM_CACHE: Arc<Mutex<systems::ram_cache::RamCache>> = Arc::new(Mutex::new(
+        systems::ram_cache::RamCache::new(ENV_CONFIG.ram_limit_objects, ENV_CONFIG.ram_limit_bytes)
+    ));
     static ref ENV_CONFIG: EnvConfig = EnvConfig {
         dynamic_cache_type: std::env::var("DYNAMIC_CACHE_TYPE")
             .unwrap_or("redis".to_string())
             .parse()
-            .expect("DYNAMIC_CACHE_TYPE must be 'redis' or 'ram'"),
+            .expect("DYNAMIC_CACHE_TYPE must be 'redis' or 'ram' or 'tiered'"),
         dynamic_cache_gc_interval: std::env::var("DYNAMIC_CACHE_GC_INTERVAL")
             .unwrap_or("600".to_string())
             .parse()
             .expect("DYNAMIC_CACHE_GC_INTERVAL must be a number"),
         redis_url: std::env::var("REDIS_URL").ok(),
+        redis_timeout_ms: std::env::var("REDIS_TIMEOUT_MS")
+            .unwrap_or("1000".to_string())
+            .parse()
+            .expect("REDIS_TIMEOUT_MS must be a number"),
+        tiered_ram_ttl: std::env::var("TIERED_RAM_TTL")
+            .unwrap_or("60".to_string())
+            .parse()
+            .expect("TIERED_RAM_TTL must be a number"),
         ram_limit_objects: std::env::var("RAM_LIMIT_OBJECTS")
             .unwrap_or("100000".to_string())
             .parse()
             .expect("RAM_LIMIT_OBJECTS must be a number"),
-        images_cache_type: std::env::var("IMAGES_CACHE_TYPE")
+        ram_limit_bytes: std::env::var("RAM_LIMIT_BYTES")
+            .unwrap_or("268435456".to_string())
+            .parse()
+            .expect("RAM_LIMIT_BYTES must be a number"),
+        images_cache_type: match std::env::var("IMAGES_CACHE_TYPE")
             .unwrap_or("redis".to_string())
             .parse()
-            .expect("IMAGES_CACHE_TYPE must be 'redis' or 'ram' or 's3'"),
+            .expect("IMAGES_CACHE_TYPE must be 'redis' or 'ram' or 'disk' or 's3'")
+        {
+            MediaCacheType::Disk { .. } => MediaCacheType::Disk {
+                path: std::env::var("DISK_CACHE_PATH").unwrap_or("cache".to_string()),
+                max_bytes: std::env::var("DISK_CACHE_MAX_BYTES")
+                    .unwrap_or("1073741824".to_string())
+                    .parse()
+                    .expect("DISK_CACHE_MAX_BYTES must be a number"),
+            },
+            MediaCacheType::S3 { .. } => MediaCacheType::S3 {
+                endpoint: std::env::var("S3_BUCKET_ENDPOINT")
+                    .expect("S3_BUCKET_ENDPOINT must be set when IMAGES_CACHE_TYPE is 's3'"),
+                bucket: std::env::var("S3_BUCKET_NAME")
+                    .expect("S3_BUCKET_NAME must be set when IMAGES_CACHE_TYPE is 's3'"),
+                region: std::env::var("S3_BUCKET_REGION").unwrap_or("us-east-1".to_string()),
+                access_key: std::env::var("S3_BUCKET_ACCESS_KEY_ID")
+                    .expect("S3_BUCKET_ACCESS_KEY_ID must be set when IMAGES_CACHE_TYPE is 's3'"),
+                secret_key: std::env::var("S3_BUCKET_SECRET_ACCESS_KEY").expect(
+                    "S3_BUCKET_SECRET_ACCESS_KEY must be set when IMAGES_CACHE_TYPE is 's3'",
+                ),
+                timeout_ms: std::env::var("S3_TIMEOUT_MS")
+                    .unwrap_or("5000".to_string())
+                    .parse()
+                    .expect("S3_TIMEOUT_MS must be a number"),
+            },
+            media_cache_type => media_cache_type,
+        },
         image_max_width: std::env::var("IMAGE_MAX_WIDTH")
             .unwrap_or("2000".to_string())
             .parse()
@@ -103,19 +201,135 @@ lazy_static! {
             .unwrap_or("2000".to_string())
             .parse()
             .expect("IMAGE_MAX_HEIGHT must be a number"),
+        image_default_quality: std::env::var("IMAGE_DEFAULT_QUALITY")
+            .unwrap_or("80".to_string())
+            .parse()
+            .expect("IMAGE_DEFAULT_QUALITY must be a number"),
+        image_min_quality: std::env::var("IMAGE_MIN_QUALITY")
+            .unwrap_or("30".to_string())
+            .parse()
+            .expect("IMAGE_MIN_QUALITY must be a number"),
+        image_max_quality: std::env::var("IMAGE_MAX_QUALITY")
+            .unwrap_or("95".to_string())
+            .parse()
+            .expect("IMAGE_MAX_QUALITY must be a number"),
+        image_max_source_width: std::env::var("IMAGE_MAX_SOURCE_WIDTH")
+            .unwrap_or("10000".to_string())
+            .parse()
+            .expect("IMAGE_MAX_SOURCE_WIDTH must be a number"),
+        image_max_source_height: std::env::var("IMAGE_MAX_SOURCE_HEIGHT")
+            .unwrap_or("10000".to_string())
+            .parse()
+            .expect("IMAGE_MAX_SOURCE_HEIGHT must be a number"),
+        image_max_source_pixels: std::env::var("IMAGE_MAX_SOURCE_PIXELS")
+            .unwrap_or("40000000".to_string())
+            .parse()
+            .expect("IMAGE_MAX_SOURCE_PIXELS must be a number"),
+        image_max_source_frames: std::env::var("IMAGE_MAX_SOURCE_FRAMES")
+            .unwrap_or("500".to_string())
+            .parse()
+            .expect("IMAGE_MAX_SOURCE_FRAMES must be a number"),
+        image_max_decode_bytes: std::env::var("IMAGE_MAX_DECODE_BYTES")
+            .unwrap_or("536870912".to_string())
+            .parse()
+            .expect("IMAGE_MAX_DECODE_BYTES must be a number"),
+        image_presets: std::env::var("IMAGE_PRESETS")
+            .unwrap_or_default()
+            .split(';')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (name, parameters) = s
+                    .split_once(':')
+                    .expect("IMAGE_PRESETS must be a list of name:parameters");
+                let preset = web::Query::<systems::image_cache::Preset>::from_query(parameters)
+                    .expect("IMAGE_PRESETS parameters must be image_proxy parameters");
+                (name.trim().to_string(), preset.into_inner())
+            })
+            .collect(),
+        image_custom_sizes: std::env::var("IMAGE_CUSTOM_SIZES")
+            .unwrap_or("true".to_string())
+            .parse()
+            .expect("IMAGE_CUSTOM_SIZES must be 'true' or 'false'"),
+        image_width_ladder: {
+            let mut ladder: Vec<u32> = std::env::var("IMAGE_WIDTH_LADDER")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.trim()
+                        .parse()
+                        .expect("IMAGE_WIDTH_LADDER must be a list of widths")
+                })
+                .collect();
+            ladder.sort_unstable();
+            ladder
+        },
         restricted_pubkeys: std::env::var("RESTRICTED_PUBKEYS")
-            .unwrap_or(String::new())
+            .unwrap_or_default()
             .split(',')
             .map(|s| s.to_string())
             .filter(|s| !s.is_empty())
             .collect(),
         password: std::env::var("PASSWORD").ok(),
+        image_signing_key: std::env::var("IMAGE_SIGNING_KEY")
+            .ok()
+            .filter(|s| !s.is_empty()),
+        image_require_signature: std::env::var("IMAGE_REQUIRE_SIGNATURE")
+            .unwrap_or("false".to_string())
+            .parse()
+            .expect("IMAGE_REQUIRE_SIGNATURE must be 'true' or 'false'"),
         restricted_images: std::env::var("RESTRICTED_IMAGES")
-            .unwrap_or(String::new())
+            .unwrap_or_default()
             .split(',')
             .filter(|s| !s.is_empty())
             .map(|s| s.parse().expect("RESTRICTED_IMAGES must be 'nsfw'"))
             .collect(),
+        fetch_allowed_hosts: std::env::var("FETCH_ALLOWED_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().trim_end_matches('.').to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        fetch_denied_hosts: std::env::var("FETCH_DENIED_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().trim_end_matches('.').to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        fetch_allowed_ports: std::env::var("FETCH_ALLOWED_PORTS")
+            .unwrap_or("80,443".to_string())
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s
+                .trim()
+                .parse()
+                .expect("FETCH_ALLOWED_PORTS must be a list of ports"))
+            .collect(),
+        fetch_allow_private_ips: std::env::var("FETCH_ALLOW_PRIVATE_IPS")
+            .unwrap_or("false".to_string())
+            .parse()
+            .expect("FETCH_ALLOW_PRIVATE_IPS must be 'true' or 'false'"),
+        fetch_max_body_bytes: std::env::var("FETCH_MAX_BODY_BYTES")
+            .unwrap_or("20971520".to_string())
+            .parse()
+            .expect("FETCH_MAX_BODY_BYTES must be a number"),
+        fetch_max_redirects: std::env::var("FETCH_MAX_REDIRECTS")
+            .unwrap_or("5".to_string())
+            .parse()
+            .expect("FETCH_MAX_REDIRECTS must be a number"),
+        fetch_connect_timeout_ms: std::env::var("FETCH_CONNECT_TIMEOUT_MS")
+            .unwrap_or("5000".to_string())
+            .parse()
+            .expect("FETCH_CONNECT_TIMEOUT_MS must be a number"),
+        fetch_read_timeout_ms: std::env::var("FETCH_READ_TIMEOUT_MS")
+            .unwrap_or("10000".to_string())
+            .parse()
+            .expect("FETCH_READ_TIMEOUT_MS must be a number"),
+        fetch_timeout_ms: std::env::var("FETCH_TIMEOUT_MS")
+            .unwrap_or("30000".to_string())
+            .parse()
+            .expect("FETCH_TIMEOUT_MS must be a number"),
         cache_ttl_nip05: std::env::var("CACHE_TTL_NIP05")
             .unwrap_or("3600".to_string())
             .parse()
@@ -124,6 +338,10 @@ lazy_static! {
             .unwrap_or("3600".to_string())
             .parse()
             .expect("CACHE_TTL_IMAGES must be a number"),
+        cache_ttl_image_info: std::env::var("CACHE_TTL_IMAGE_INFO")
+            .unwrap_or("3600".to_string())
+            .parse()
+            .expect("CACHE_TTL_IMAGE_INFO must be a number"),
         cache_ttl_webpreview: std::env::var("CACHE_TTL_WEBPREVIEW")
             .unwrap_or("3600".to_string())
             .parse()
@@ -137,6 +355,20 @@ lazy_static! {
 
 pub struct WebStates {
     pub cache: cache::Cache,
+    pub media_cache: cache::MediaCache,
+    pub fetcher: systems::fetcher::Fetcher,
+}
+
+async fn connect_redis() -> systems::redis_cache::RedisBackend {
+    systems::redis_cache::RedisBackend::connect(
+        ENV_CONFIG
+            .redis_url
+            .as_ref()
+            .expect("REDIS_URL must be set when DYNAMIC_CACHE_TYPE is 'redis' or 'tiered'"),
+        std::time::Duration::from_millis(ENV_CONFIG.redis_timeout_ms),
+    )
+    .await
+    .unwrap()
 }
 
 #[tokio::main]
@@ -145,7 +377,9 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
 
     // Run a thread to clean the RAM cache
-    if ENV_CONFIG.dynamic_cache_type == DynamicCacheType::RAM {
+    if ENV_CONFIG.dynamic_cache_type != DynamicCacheType::REDIS
+        || matches!(ENV_CONFIG.images_cache_type, MediaCacheType::RAM)
+    {
         let ram_cache = RAM_CACHE.clone();
         tokio::spawn(async move {
             loop {
@@ -157,21 +391,107 @@ async fn main() -> std::io::Result<()> {
         });
     }
 
-    let cache = cache::Cache::new({
-        if ENV_CONFIG.dynamic_cache_type == DynamicCacheType::REDIS {
-            Some(std::env::var("REDIS_URL").unwrap())
-        } else {
-            None
+    let cache = match ENV_CONFIG.dynamic_cache_type {
+        DynamicCacheType::REDIS => cache::Cache::new(connect_redis().await),
+        DynamicCacheType::RAM => {
+            cache::Cache::new(systems::ram_cache::RamBackend::new(RAM_CACHE.clone()))
+        }
+        DynamicCacheType::TIERED => cache::Cache::new(systems::tiered_cache::TieredBackend::new(
+            systems::ram_cache::RamBackend::new(RAM_CACHE.clone()),
+            connect_redis().await,
+            ENV_CONFIG.tiered_ram_ttl,
+        )),
+    };
+
+    if ENV_CONFIG.image_require_signature && ENV_CONFIG.image_signing_key.is_none() {
+        panic!("IMAGE_SIGNING_KEY must be set when IMAGE_REQUIRE_SIGNATURE is 'true'");
+    }
+
+    // Fail fast on a preset with invalid parameters
+    for (name, preset) in &ENV_CONFIG.image_presets {
+        if let Err(err) = preset.validate() {
+            panic!("Invalid preset {name} in IMAGE_PRESETS: {err}");
+        }
+    }
+
+    let media_cache = match &ENV_CONFIG.images_cache_type {
+        // The medias share the dynamic cache
+        MediaCacheType::Redis => cache::MediaCache::new(cache::CacheMediaStore::new(cache.clone())),
+        MediaCacheType::RAM => cache::MediaCache::new(cache::CacheMediaStore::new(
+            cache::Cache::new(systems::ram_cache::RamBackend::new(RAM_CACHE.clone())),
+        )),
+        MediaCacheType::Disk { path, max_bytes } => {
+            let disk_cache = Arc::new(Mutex::new(
+                systems::disk_cache::DiskCache::open(path, *max_bytes)
+                    .expect("Unable to open the disk cache directory"),
+            ));
+
+            // Run a thread to remove the expired medias from the disk cache
+            let gc_disk_cache = disk_cache.clone();
+            tokio::spawn(async move {
+                loop {
+                    systems::disk_cache::gc(&gc_disk_cache).await;
+                    tokio::time::sleep(std::time::Duration::from_secs(
+                        ENV_CONFIG.dynamic_cache_gc_interval as u64,
+                    ))
+                    .await;
+                }
+            });
+
+            cache::MediaCache::new(systems::disk_cache::DiskMediaStore::new(disk_cache))
+        }
+        MediaCacheType::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            timeout_ms,
+        } => {
+            let bucket = systems::s3::S3Bucket::new(
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_key,
+                std::time::Duration::from_millis(*timeout_ms),
+            )
+            .expect("S3_BUCKET_ENDPOINT must be a valid URL");
+
+            // Fail fast if the S3 bucket is not reachable with the given credentials
+            bucket
+                .head_bucket()
+                .await
+                .expect("Unable to reach the S3 bucket");
+
+            cache::MediaCache::new(bucket)
         }
+    };
+
+    let fetcher = systems::fetcher::Fetcher::new(systems::fetcher::FetchPolicy {
+        allowed_hosts: ENV_CONFIG.fetch_allowed_hosts.clone(),
+        denied_hosts: ENV_CONFIG.fetch_denied_hosts.clone(),
+        allowed_ports: ENV_CONFIG.fetch_allowed_ports.clone(),
+        allow_private_ips: ENV_CONFIG.fetch_allow_private_ips,
+        max_body_bytes: ENV_CONFIG.fetch_max_body_bytes,
+        max_redirects: ENV_CONFIG.fetch_max_redirects,
+        connect_timeout: std::time::Duration::from_millis(ENV_CONFIG.fetch_connect_timeout_ms),
+        read_timeout: std::time::Duration::from_millis(ENV_CONFIG.fetch_read_timeout_ms),
+        total_timeout: std::time::Duration::from_millis(ENV_CONFIG.fetch_timeout_ms),
     })
-    .await
-    .unwrap();
+    .expect("Unable to build the HTTP client");
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(WebStates {
                 cache: cache.clone(),
+                media_cache: media_cache.clone(),
+                fetcher: fetcher.clone(),
             }))
+            .app_data(
+                web::QueryConfig::default().error_handler(handlers::error::query_error_handler),
+            )
+            .app_data(web::PathConfig::default().error_handler(handlers::error::path_error_handler))
             .wrap(crate::middlewares::time_mesure::TimeMesure)
             .route("/", web::get().to(handlers::index::get))
             .wrap(crate::middlewares::validate::Validate)
@@ -183,8 +503,18 @@ async fn main() -> std::io::Result<()> {
                     .max_age(3600),
             )
             .route("/is_good", web::get().to(handlers::verify::get))
+            .route("/stats", web::get().to(handlers::stats::get))
             .route("/nip05", web::get().to(handlers::nip05::get))
             .route("/image_proxy", web::get().to(handlers::image_proxy::get))
+            .route(
+                "/i/{options}/{source}",
+                web::get().to(handlers::image_path::get),
+            )
+            .route("/image_info", web::get().to(handlers::image_info::get))
+            .route(
+                "/image_placeholder",
+                web::get().to(handlers::image_placeholder::get),
+            )
             .route(
                 "/website_preview",
                 web::get().to(handlers::website_previews::get),
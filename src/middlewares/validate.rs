@@ -1,4 +1,7 @@
-use crate::{systems::url::parse_query_string, WebStates};
+use crate::{
+    systems::{security, url::parse_query_string},
+    WebStates,
+};
 use actix_web::{
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
     Error, HttpResponse,
@@ -48,21 +51,33 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let search_params = parse_query_string(req.request().query_string());
+        let signature = security::check_url_signature(req.path(), req.query_string());
+        let requires_signature = security::requires_signature(req.path());
         let svc = self.service.clone();
 
         Box::pin(async move {
             let state = req.app_data::<actix_web::web::Data<WebStates>>().unwrap();
 
-            if crate::systems::security::check_access(
-                &state.cache,
-                search_params.get("pubkey"),
-                search_params.get("sig"),
-                search_params.get("time"),
-                search_params.get("uniq"),
-                search_params.get("pass"),
-            )
-            .await
-            {
+            let allowed = match signature {
+                Some(valid) => valid,
+                None if requires_signature => {
+                    println!("Unsigned request");
+                    false
+                }
+                None => {
+                    security::check_access(
+                        &state.cache,
+                        search_params.get("pubkey"),
+                        search_params.get("sig"),
+                        search_params.get("time"),
+                        search_params.get("uniq"),
+                        search_params.get("pass"),
+                    )
+                    .await
+                }
+            };
+
+            if allowed {
                 let res = svc.call(req).await?;
                 Ok(res)
             } else {
@@ -1,5 +1,4 @@
-use async_lock::Mutex;
-use redis::AsyncCommands;
+use async_trait::async_trait;
 use std::sync::Arc;
 use thiserror::Error;
 
@@ -10,71 +9,47 @@ pub enum CacheError {
 
     #[error("Data not found")]
     NotFound,
+
+    #[error("Cache timed out")]
+    Timeout,
+
+    #[error("Cache temporarily unavailable")]
+    Unavailable,
+}
+
+/// Storage used by `Cache`, implemented by `RedisBackend` and `RamBackend`
+///
+/// A missing or expired key must return `CacheError::NotFound`.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get_str(&self, key: &str) -> Result<String, CacheError>;
+
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError>;
+
+    async fn set_str(&self, key: &str, value: &str, expiration: usize) -> Result<(), CacheError>;
+
+    async fn set_bytes(&self, key: &str, value: &[u8], expiration: usize)
+        -> Result<(), CacheError>;
 }
 
 #[derive(Clone)]
 pub struct Cache {
-    pub client: Option<redis::Client>,
-    pub connection: Option<Arc<Mutex<redis::aio::Connection>>>,
+    backend: Arc<dyn CacheBackend>,
 }
 
 impl Cache {
-    pub async fn new(redis_url: Option<String>) -> Result<Self, CacheError> {
-        if let Some(redis_url) = redis_url {
-            let client = redis::Client::open(redis_url)?;
-            let connection = client.get_async_connection().await?;
-            let connection = Arc::new(Mutex::new(connection));
-
-            return Ok(Self {
-                client: Some(client),
-                connection: Some(connection),
-            });
+    pub fn new(backend: impl CacheBackend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
         }
-
-        Ok(Self {
-            client: None,
-            connection: None,
-        })
     }
 
     pub async fn get_str(&self, key: &str) -> Result<String, CacheError> {
-        match crate::ENV_CONFIG.dynamic_cache_type {
-            crate::DynamicCacheType::REDIS => {
-                let mut connection = self.connection.as_ref().unwrap().lock().await;
-                let value: String = connection.get(key).await?;
-
-                Ok(value)
-            }
-            crate::DynamicCacheType::RAM => {
-                let cache = crate::RAM_CACHE.lock().await;
-                let value = cache.get_str(key);
-
-                match value {
-                    Some(value) => Ok(value.to_string()),
-                    None => Err(CacheError::NotFound),
-                }
-            }
-        }
+        self.backend.get_str(key).await
     }
 
     pub async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
-        match crate::ENV_CONFIG.dynamic_cache_type {
-            crate::DynamicCacheType::REDIS => {
-                let mut connection = self.connection.as_ref().unwrap().lock().await;
-                let value: Vec<u8> = connection.get(key).await?;
-
-                Ok(value)
-            }
-            crate::DynamicCacheType::RAM => {
-                let cache = crate::RAM_CACHE.lock().await;
-                let value = cache.get_image(key);
-
-                match value {
-                    Some(value) => Ok(value.to_vec()),
-                    None => Err(CacheError::NotFound),
-                }
-            }
-        }
+        self.backend.get_bytes(key).await
     }
 
     pub async fn set_str(
@@ -85,143 +60,153 @@ impl Cache {
     ) -> Result<(), CacheError> {
         println!("Set cache key: {key}");
 
-        match crate::ENV_CONFIG.dynamic_cache_type {
-            crate::DynamicCacheType::REDIS => {
-                let mut connection = self.connection.as_ref().unwrap().lock().await;
-                connection.set(key, value).await?;
-                connection.expire(key, expiration).await?;
-            }
-            crate::DynamicCacheType::RAM => {
-                let mut cache = crate::RAM_CACHE.lock().await;
-                cache.set_str(key, value.to_string(), expiration);
-            }
-        }
-
-        Ok(())
+        self.backend.set_str(key, value, expiration).await
     }
 
     pub async fn set_bytes(
         &self,
         key: &str,
-        value: &Vec<u8>,
+        value: &[u8],
         expiration: usize,
     ) -> Result<(), CacheError> {
         println!("Set cache key: {key}");
 
-        match crate::ENV_CONFIG.dynamic_cache_type {
-            crate::DynamicCacheType::REDIS => {
-                let mut connection = self.connection.as_ref().unwrap().lock().await;
-                connection.set(key, value).await?;
-                connection.expire(key, expiration).await?;
-            }
-            crate::DynamicCacheType::RAM => {
-                let mut cache = crate::RAM_CACHE.lock().await;
-                cache.set_image(key, value, expiration);
-            }
+        self.backend.set_bytes(key, value, expiration).await
+    }
+}
+
+/// Storage of the encoded medias used by `MediaCache`, implemented by `CacheMediaStore`,
+/// `DiskMediaStore` and `S3Bucket`
+///
+/// A missing or expired media must return None, the errors are logged by the store.
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    async fn get(&self, file_name: &str) -> Option<(Vec<u8>, String)>;
+
+    async fn set(&self, file_name: &str, content: &[u8], mime_type: &str, expiration: usize);
+}
+
+#[derive(Clone)]
+pub struct MediaCache {
+    store: Arc<dyn MediaStore>,
+}
+
+impl MediaCache {
+    pub fn new(store: impl MediaStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
         }
+    }
+
+    pub async fn get(&self, file_name: &str) -> Option<(Vec<u8>, String)> {
+        self.store
+            .get(file_name)
+            .await
+            .filter(|(content, _)| !content.is_empty())
+    }
 
-        Ok(())
+    pub async fn set(&self, file_name: &str, content: &[u8], mime_type: &str, expiration: usize) {
+        self.store
+            .set(file_name, content, mime_type, expiration)
+            .await
     }
 }
 
-// Replace all specials chars which are not compatible with file name with "_" but keep "."
-// https://hello.world -> https___hello.world
-/*
-pub fn normalize_file_name(url: &str) -> String {
-    url.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_")
-}*/
-
-pub async fn set_media_cache(file_name: &str, content: &Vec<u8>, cache: &Cache) {
-    let cache_key = format!("media_media:{file_name}");
-
-    use crate::MediaCacheType::*;
-    match crate::ENV_CONFIG.images_cache_type.to_owned() {
-        Redis => {
-            cache
-                .set_bytes(&cache_key, content, crate::ENV_CONFIG.cache_ttl_images)
-                .await
-                .unwrap();
-        }
-        RAM => {
-            let mut cache = crate::RAM_CACHE.lock().await;
-            cache.set_image(&cache_key, content, crate::ENV_CONFIG.cache_ttl_images);
-        }
-        // DiskDir(folder_path) => {
-        //     let mut file_path = std::path::PathBuf::from(folder_path);
-        //     file_path.push(normalize_file_name(file_name));
-
-        //     let mut file = std::fs::File::create(file_path).unwrap();
-        //     file.write_all(&content).unwrap();
-        // }
-        S3 {
-            bucket,
-            region,
-            access_key,
-            secret_key,
-        } => todo!(),
+/// `MediaStore` keeping the medias in a `Cache`, with their mime type under a "+ext" key
+pub struct CacheMediaStore {
+    cache: Cache,
+}
+
+impl CacheMediaStore {
+    pub fn new(cache: Cache) -> Self {
+        Self { cache }
     }
 }
 
-pub async fn get_media_cache(file_name: &str, cache: &Cache) -> Option<(Vec<u8>, String)> {
-    let cache_key = format!("media_media:{file_name}");
-    let ext_key = format!("{file_name}+ext");
-
-    use crate::MediaCacheType::*;
-    match crate::ENV_CONFIG.images_cache_type.to_owned() {
-        Redis => {
-            let cache_response = cache.get_bytes(&cache_key).await;
-            if let Ok(cache_response) = cache_response {
-                if cache_response.is_empty() {
-                    return None;
-                }
-
-                Some((
-                    cache_response.to_vec(),
-                    cache.get_str(&ext_key).await.unwrap_or(String::new()),
-                ))
-            } else {
-                None
-            }
+#[async_trait]
+impl MediaStore for CacheMediaStore {
+    async fn get(&self, file_name: &str) -> Option<(Vec<u8>, String)> {
+        let content = self
+            .cache
+            .get_bytes(&format!("media_media:{file_name}"))
+            .await
+            .ok()?;
+        let mime_type = self
+            .cache
+            .get_str(&format!("{file_name}+ext"))
+            .await
+            .unwrap_or_default();
+
+        Some((content, mime_type))
+    }
+
+    async fn set(&self, file_name: &str, content: &[u8], mime_type: &str, expiration: usize) {
+        if let Err(err) = self
+            .cache
+            .set_bytes(&format!("media_media:{file_name}"), content, expiration)
+            .await
+        {
+            println!("Cache error: {err}");
+            return;
         }
-        RAM => {
-            let cache = crate::RAM_CACHE.lock().await;
-            let cache_response = cache.get_image(&cache_key);
-
-            if let Some(cache_response) = cache_response {
-                if cache_response.is_empty() {
-                    return None;
-                }
-
-                Some((
-                    cache_response.to_vec(),
-                    cache
-                        .get_str(&ext_key)
-                        .unwrap_or(&String::new())
-                        .to_string(),
-                ))
-            } else {
-                None
-            }
+        if let Err(err) = self
+            .cache
+            .set_str(&format!("{file_name}+ext"), mime_type, expiration)
+            .await
+        {
+            println!("Cache error: {err}");
         }
-        // DiskDir(folder_path) => {
-        //     let mut file_path = std::path::PathBuf::from(folder_path);
-        //     file_path.push(normalize_file_name(file_name));
-        //     if file_path.exists() {
-        //         let content = std::fs::read(&file_path).unwrap();
-        //         let mime_type = mime_guess::from_path(file_path)
-        //             .first_or_octet_stream()
-        //             .to_string();
-
-        //         Some((content, mime_type))
-        //     } else {
-        //         None
-        //     }
-        // }
-        S3 {
-            bucket,
-            region,
-            access_key,
-            secret_key,
-        } => todo!(),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::systems::ram_cache::{RamBackend, RamCache};
+    use async_lock::Mutex;
+
+    pub fn ram_cache() -> Cache {
+        Cache::new(RamBackend::new(Arc::new(Mutex::new(RamCache::new(
+            100, 1_000_000,
+        )))))
+    }
+
+    #[tokio::test]
+    async fn stores_strings_and_bytes() {
+        let cache = ram_cache();
+
+        assert!(matches!(
+            cache.get_str("a").await,
+            Err(CacheError::NotFound)
+        ));
+        cache.set_str("a", "value", 60).await.unwrap();
+        cache.set_bytes("b", b"bytes", 60).await.unwrap();
+
+        assert_eq!(cache.get_str("a").await.unwrap(), "value");
+        assert_eq!(cache.get_bytes("b").await.unwrap(), b"bytes");
+        // Strings and bytes are separate namespaces
+        assert!(cache.get_bytes("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_medias_with_their_mime_type() {
+        let media_cache = MediaCache::new(CacheMediaStore::new(ram_cache()));
+
+        assert_eq!(media_cache.get("image.png").await, None);
+        media_cache
+            .set("image.png", b"content", "image/png", 60)
+            .await;
+        assert_eq!(
+            media_cache.get("image.png").await,
+            Some((b"content".to_vec(), "image/png".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_empty_medias() {
+        let media_cache = MediaCache::new(CacheMediaStore::new(ram_cache()));
+
+        media_cache.set("image.png", b"", "image/png", 60).await;
+        assert_eq!(media_cache.get("image.png").await, None);
     }
 }
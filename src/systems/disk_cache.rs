@@ -0,0 +1,431 @@
+use async_lock::Mutex;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use super::cache::MediaStore;
+
+// Makes the temporary files of concurrent writes of the same media unique
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Sidecar file stored next to each cached media
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    mime_type: String,
+    expires: usize,
+}
+
+struct DiskEntry {
+    size: u64,
+    mime_type: String,
+    expires: usize,
+    last_access: u64,
+}
+
+/// Media cache stored on the local disk
+///
+/// Each media is stored under `<root>/<h[0..2]>/<h[2..4]>/<h>` where `h` is the sha256 of its
+/// cache key, with a `<h>.meta` JSON sidecar holding its mime type and expiration.
+/// The index is kept in memory and rebuilt from the sidecars at startup.
+/// When the total size exceeds `max_bytes`, the least recently used medias are removed.
+///
+/// The struct is only the index, the files are read, written and removed by `get`, `set`
+/// and `gc` on the blocking thread pool, without holding the lock of the index.
+pub struct DiskCache {
+    root: PathBuf,
+    max_bytes: u64,
+    total_bytes: u64,
+    tick: u64,
+    entries: HashMap<String, DiskEntry>,
+    // last_access -> hash, the first item is the least recently used
+    lru: BTreeMap<u64, String>,
+    // Files of the medias removed from the index, deleted once the lock is released
+    removed_files: Vec<PathBuf>,
+}
+
+/// Read a media, None if it is missing or expired
+pub async fn get(cache: &Mutex<DiskCache>, key: &str) -> Option<(Vec<u8>, String)> {
+    let hash = sha256::digest(key);
+    let (found, removed_files) = {
+        let mut cache = cache.lock().await;
+        let found = cache.lookup(&hash);
+        (found, cache.take_removed_files())
+    };
+    remove_files(removed_files).await;
+
+    let (path, mime_type) = found?;
+    match tokio::task::spawn_blocking(move || std::fs::read(path)).await {
+        Ok(Ok(content)) => Some((content, mime_type)),
+        _ => {
+            // Removed behind our back, or evicted while it was read
+            let removed_files = {
+                let mut cache = cache.lock().await;
+                cache.remove(&hash);
+                cache.take_removed_files()
+            };
+            remove_files(removed_files).await;
+            None
+        }
+    }
+}
+
+/// Store a media then evict the least recently used ones if the size budget is exceeded
+pub async fn set(cache: &Mutex<DiskCache>, key: &str, content: &[u8], mime_type: &str, ttl: usize) {
+    let hash = sha256::digest(key);
+    let path = cache.lock().await.path(&hash);
+    let expires = now() + ttl;
+
+    let written = {
+        let content = content.to_vec();
+        let mime_type = mime_type.to_string();
+        tokio::task::spawn_blocking(move || write_files(&path, &content, &mime_type, expires))
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    };
+
+    let removed_files = {
+        let mut cache = cache.lock().await;
+        match written {
+            Ok(()) => cache.insert(hash, content.len() as u64, mime_type.to_string(), expires),
+            Err(err) => {
+                println!("Disk cache write error: {err}");
+                cache.remove(&hash);
+            }
+        }
+        cache.take_removed_files()
+    };
+    remove_files(removed_files).await;
+}
+
+/// Remove the expired medias
+pub async fn gc(cache: &Mutex<DiskCache>) {
+    let removed_files = {
+        let mut cache = cache.lock().await;
+        cache.gc();
+        cache.take_removed_files()
+    };
+    remove_files(removed_files).await;
+}
+
+/// `MediaStore` keeping the medias in a `DiskCache`, the mime type is stored in the sidecar
+pub struct DiskMediaStore {
+    cache: Arc<Mutex<DiskCache>>,
+}
+
+impl DiskMediaStore {
+    pub fn new(cache: Arc<Mutex<DiskCache>>) -> Self {
+        Self { cache }
+    }
+}
+
+#[async_trait]
+impl MediaStore for DiskMediaStore {
+    async fn get(&self, file_name: &str) -> Option<(Vec<u8>, String)> {
+        get(&self.cache, &format!("media_media:{file_name}")).await
+    }
+
+    async fn set(&self, file_name: &str, content: &[u8], mime_type: &str, expiration: usize) {
+        set(
+            &self.cache,
+            &format!("media_media:{file_name}"),
+            content,
+            mime_type,
+            expiration,
+        )
+        .await
+    }
+}
+
+async fn remove_files(paths: Vec<PathBuf>) {
+    if paths.is_empty() {
+        return;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        for path in paths {
+            std::fs::remove_file(&path).ok();
+        }
+    })
+    .await
+    .ok();
+}
+
+impl DiskCache {
+    pub fn open(root: &str, max_bytes: u64) -> std::io::Result<Self> {
+        let mut cache = Self {
+            root: PathBuf::from(root),
+            max_bytes,
+            total_bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            removed_files: Vec::new(),
+        };
+
+        std::fs::create_dir_all(&cache.root)?;
+
+        // Rebuild the index, the oldest files are considered the least recently used
+        let mut found = Vec::new();
+        for shard in sub_dirs(&cache.root)? {
+            for sub_shard in sub_dirs(&shard)? {
+                for file in std::fs::read_dir(sub_shard)?.flatten() {
+                    let path = file.path();
+                    if let Some(extension) = path.extension() {
+                        // Leftover of an interrupted write
+                        if extension == "tmp" {
+                            std::fs::remove_file(&path).ok();
+                        }
+                        continue;
+                    }
+
+                    let hash = file.file_name().to_string_lossy().to_string();
+                    if hash.len() != 64 {
+                        continue;
+                    }
+
+                    let metadata = file.metadata()?;
+                    let sidecar = std::fs::read(path.with_extension("meta"))
+                        .ok()
+                        .and_then(|content| serde_json::from_slice::<Sidecar>(&content).ok());
+
+                    match sidecar {
+                        Some(sidecar) => {
+                            found.push((metadata.modified()?, hash, metadata.len(), sidecar))
+                        }
+                        None => {
+                            std::fs::remove_file(&path).ok();
+                        }
+                    }
+                }
+            }
+        }
+
+        found.sort_by_key(|(modified, ..)| *modified);
+        for (_, hash, size, sidecar) in found {
+            cache.insert_entry(hash, size, sidecar.mime_type, sidecar.expires);
+        }
+
+        cache.gc();
+        cache.evict();
+        for path in cache.take_removed_files() {
+            std::fs::remove_file(path).ok();
+        }
+
+        Ok(cache)
+    }
+
+    // Path and mime type of a fresh media, which becomes the most recently used
+    fn lookup(&mut self, hash: &str) -> Option<(PathBuf, String)> {
+        let entry = self.entries.get(hash)?;
+
+        if entry.expires <= now() {
+            self.remove(hash);
+            return None;
+        }
+
+        let mime_type = entry.mime_type.clone();
+        self.touch(hash);
+        Some((self.path(hash), mime_type))
+    }
+
+    fn insert(&mut self, hash: String, size: u64, mime_type: String, expires: usize) {
+        self.remove_entry(&hash);
+        self.insert_entry(hash, size, mime_type, expires);
+        self.evict();
+    }
+
+    fn gc(&mut self) {
+        let now = now();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    // Remove the least recently used medias until the size budget is respected
+    fn evict(&mut self) {
+        while self.total_bytes > self.max_bytes {
+            let hash = match self.lru.values().next() {
+                Some(hash) => hash.clone(),
+                None => break,
+            };
+
+            self.remove(&hash);
+        }
+    }
+
+    fn insert_entry(&mut self, hash: String, size: u64, mime_type: String, expires: usize) {
+        self.tick += 1;
+        self.total_bytes += size;
+        self.lru.insert(self.tick, hash.clone());
+        self.entries.insert(
+            hash,
+            DiskEntry {
+                size,
+                mime_type,
+                expires,
+                last_access: self.tick,
+            },
+        );
+    }
+
+    fn remove_entry(&mut self, hash: &str) {
+        if let Some(entry) = self.entries.remove(hash) {
+            self.total_bytes -= entry.size;
+            self.lru.remove(&entry.last_access);
+        }
+    }
+
+    fn touch(&mut self, hash: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            self.lru.remove(&entry.last_access);
+            entry.last_access = self.tick;
+            self.lru.insert(self.tick, hash.to_string());
+        }
+    }
+
+    fn remove(&mut self, hash: &str) {
+        self.remove_entry(hash);
+
+        let path = self.path(hash);
+        self.removed_files.push(path.with_extension("meta"));
+        self.removed_files.push(path);
+    }
+
+    fn take_removed_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.removed_files)
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.push(&hash[0..2]);
+        path.push(&hash[2..4]);
+        path.push(hash);
+        path
+    }
+}
+
+// Write the content and its sidecar through temporary files so a crash never leaves a partial media
+fn write_files(
+    path: &Path,
+    content: &[u8],
+    mime_type: &str,
+    expires: usize,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let sidecar = serde_json::to_vec(&Sidecar {
+        mime_type: mime_type.to_string(),
+        expires,
+    })?;
+
+    let tmp_id = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let tmp_path = path.with_extension(format!("{tmp_id}.tmp"));
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+
+    let tmp_path = path.with_extension(format!("meta.{tmp_id}.tmp"));
+    std::fs::write(&tmp_path, sidecar)?;
+    std::fs::rename(&tmp_path, path.with_extension("meta"))?;
+
+    Ok(())
+}
+
+fn sub_dirs(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    Ok(std::fs::read_dir(path)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect())
+}
+
+fn now() -> usize {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str, max_bytes: u64) -> (PathBuf, Mutex<DiskCache>) {
+        let root = std::env::temp_dir().join(format!("disk-cache-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        let cache = DiskCache::open(root.to_str().unwrap(), max_bytes).unwrap();
+
+        (root, Mutex::new(cache))
+    }
+
+    #[tokio::test]
+    async fn stores_and_reopens() {
+        let (root, cache) = open("reopen", 1024);
+
+        set(&cache, "a", b"content", "image/png", 60).await;
+        assert_eq!(
+            get(&cache, "a").await,
+            Some((b"content".to_vec(), "image/png".to_string()))
+        );
+        assert_eq!(get(&cache, "b").await, None);
+
+        // The index is rebuilt from the sidecars
+        let cache = Mutex::new(DiskCache::open(root.to_str().unwrap(), 1024).unwrap());
+        assert_eq!(
+            get(&cache, "a").await,
+            Some((b"content".to_vec(), "image/png".to_string()))
+        );
+
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[tokio::test]
+    async fn removes_the_expired_medias() {
+        let (root, cache) = open("expired", 1024);
+
+        set(&cache, "a", b"content", "image/png", 0).await;
+        let path = cache.lock().await.path(&sha256::digest("a"));
+        assert!(path.exists());
+
+        assert_eq!(get(&cache, "a").await, None);
+        assert!(!path.exists());
+        assert!(!path.with_extension("meta").exists());
+
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used() {
+        let (root, cache) = open("evict", 10);
+
+        set(&cache, "a", b"aaaa", "image/png", 60).await;
+        set(&cache, "b", b"bbbb", "image/png", 60).await;
+        // "a" becomes the most recently used
+        assert!(get(&cache, "a").await.is_some());
+        set(&cache, "c", b"cccc", "image/png", 60).await;
+
+        assert!(get(&cache, "a").await.is_some());
+        assert_eq!(get(&cache, "b").await, None);
+        assert!(get(&cache, "c").await.is_some());
+        assert!(!cache.lock().await.path(&sha256::digest("b")).exists());
+
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -1,36 +1,318 @@
-use image::{AnimationDecoder, ImageFormat};
+use image::{imageops::FilterType, io::Limits, DynamicImage, ImageFormat, Rgba};
 use serde::Deserialize;
-use std::{
-    fs::File,
-    io::{Cursor, Read},
-    mem,
-};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io::Cursor};
 use thiserror::Error;
 
-use crate::systems::cache::{get_media_cache, set_media_cache};
+use crate::systems::images::{
+    animation, color, orientation,
+    placeholder::Placeholder,
+    resize::{color_from_param, filter_from_param, resize, Fit, Gravity, ResizeOptions},
+    svg, EncodeOptions, OutputFormat, PngCompression, SourceFormat,
+};
 
-use super::cache::Cache;
+use super::{
+    cache::{Cache, MediaCache},
+    fetcher::{FetchError, Fetcher},
+};
 
-#[derive(Debug, Deserialize, Error)]
+#[derive(Debug, Deserialize, Error, PartialEq, Eq)]
 pub enum InfoError {
     #[error("Invalid ratio format")]
     InvalidRatioFormat,
 
-    #[error("No width, height, or ratio defined")]
-    NoSizeDefined,
+    #[error("Width and height must be at least 1")]
+    SizeTooSmall,
+
+    #[error("Unsupported output format")]
+    InvalidFormat,
+
+    #[error("Quality must be between 1 and 100")]
+    InvalidQuality,
+
+    #[error("Compression must be fast, default or best")]
+    InvalidCompression,
+
+    #[error("Palette must have between 2 and 256 colors")]
+    InvalidPalette,
+
+    #[error("Fit must be contain, cover, fill, inside or pad")]
+    InvalidFit,
+
+    #[error("Filter must be nearest, triangle, catmullrom, gaussian or lanczos3")]
+    InvalidFilter,
+
+    #[error("Background must be a rrggbb or rrggbbaa hex color")]
+    InvalidBackground,
+
+    #[error("Gravity must be center, north, south, east, west or attention")]
+    InvalidGravity,
+
+    #[error("Focus must be x,y with both coordinates between 0 and 1")]
+    InvalidFocus,
+
+    #[error("Frame must be first and still must be 0 or 1")]
+    InvalidFrame,
+
+    #[error("Hash must be blurhash or thumbhash")]
+    InvalidPlaceholder,
+
+    #[error("Unknown preset")]
+    InvalidPreset,
+
+    #[error("Width, height and ratio can't be combined with a preset")]
+    PresetWithSize,
+
+    #[error("Only the presets can set a width, a height or a ratio")]
+    CustomSizeForbidden,
+
+    #[error("Source must be a base64url encoded URL followed by an extension")]
+    InvalidSource,
+
+    #[error("Options must be image_proxy parameters separated by &, or _ for none")]
+    InvalidOptions,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Default, Deserialize)]
 pub struct Info {
     pub url: String,
+    pub preset: Option<String>, // Name of a preset of IMAGE_PRESETS
     pub width: Option<f64>,
     pub height: Option<f64>,
-    pub ratio: Option<String>, // Format: "width:height"
+    pub ratio: Option<String>,  // Format: "width:height"
+    pub format: Option<String>, // Overrides the Accept header: "avif", "webp", "jpeg", "png", "gif" or "webm"
+    pub quality: Option<u8>,
+    pub lossless: Option<bool>,
+    pub compression: Option<String>, // "fast", "default" or "best"
+    pub palette: Option<u16>,
+    pub fit: Option<String>, // "contain", "cover", "fill", "inside" or "pad"
+    pub filter: Option<String>, // "nearest", "triangle", "catmullrom", "gaussian" or "lanczos3"
+    pub background: Option<String>, // Format: "rrggbb" or "rrggbbaa"
+    pub gravity: Option<String>, // "center", "north", "south", "east", "west" or "attention"
+    pub focus: Option<String>, // Format: "x,y", overrides gravity
+    pub frame: Option<String>, // "first" to get a still of an animation
+    pub still: Option<String>, // "1" to get a still of an animation, same as frame=first
+}
+
+/// Named set of parameters configured by IMAGE_PRESETS, e.g. `avatar_64:width=64&ratio=1:1`
+#[derive(Clone, Deserialize)]
+pub struct Preset {
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub ratio: Option<String>,
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub lossless: Option<bool>,
+    pub compression: Option<String>,
+    pub palette: Option<u16>,
+    pub fit: Option<String>,
+    pub filter: Option<String>,
+    pub background: Option<String>,
+    pub gravity: Option<String>,
+    pub focus: Option<String>,
+    pub frame: Option<String>,
+    pub still: Option<String>,
+}
+
+impl Preset {
+    /// Check the parameters of the preset once, at startup
+    pub fn validate(&self) -> Result<(), InfoError> {
+        let info = Info::default().with_preset(self);
+
+        if let Some(format) = &info.format {
+            OutputFormat::from_param(format).ok_or(InfoError::InvalidFormat)?;
+        }
+        info.get_encode_options()?;
+        info.get_resize_options()?;
+        info.is_still()?;
+        info.get_new_size(1.0, 1.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MetadataInfo {
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+pub struct PlaceholderInfo {
+    pub url: String,
+    pub hash: Option<String>, // "blurhash" or "thumbhash"
 }
 
 impl Info {
+    /// Apply the preset or the size policy, the result is what the cache key is built from
+    pub fn resolve(&self) -> Result<Info, InfoError> {
+        let config = &crate::ENV_CONFIG;
+
+        self.resolve_with(
+            &config.image_presets,
+            config.image_custom_sizes,
+            &config.image_width_ladder,
+        )
+    }
+
+    fn resolve_with(
+        &self,
+        presets: &HashMap<String, Preset>,
+        custom_sizes: bool,
+        ladder: &[u32],
+    ) -> Result<Info, InfoError> {
+        let has_size = self.width.is_some() || self.height.is_some() || self.ratio.is_some();
+
+        if let Some(name) = &self.preset {
+            let preset = presets.get(name).ok_or(InfoError::InvalidPreset)?;
+            if has_size {
+                return Err(InfoError::PresetWithSize);
+            }
+
+            return Ok(self.with_preset(preset));
+        }
+
+        if has_size && !custom_sizes {
+            return Err(InfoError::CustomSizeForbidden);
+        }
+
+        // A side given alone is snapped, a height given with the width follows the snapped width
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) if width > 0.0 => {
+                let snapped_width = snap_to_ladder(width, ladder);
+                let height = match snapped_width == width {
+                    true => height,
+                    false => (height * snapped_width / width).round().max(1.0),
+                };
+
+                (Some(snapped_width), Some(height))
+            }
+            (width, height) => (
+                width.map(|width| snap_to_ladder(width, ladder)),
+                height.map(|height| snap_to_ladder(height, ladder)),
+            ),
+        };
+
+        Ok(Info {
+            width,
+            height,
+            ..self.clone()
+        })
+    }
+
+    // The parameters set by the preset replace the ones of the request
+    fn with_preset(&self, preset: &Preset) -> Info {
+        let preset = preset.clone();
+
+        Info {
+            url: self.url.clone(),
+            preset: None,
+            width: preset.width,
+            height: preset.height,
+            ratio: preset.ratio,
+            format: preset.format.or_else(|| self.format.clone()),
+            quality: preset.quality.or(self.quality),
+            lossless: preset.lossless.or(self.lossless),
+            compression: preset.compression.or_else(|| self.compression.clone()),
+            palette: preset.palette.or(self.palette),
+            fit: preset.fit.or_else(|| self.fit.clone()),
+            filter: preset.filter.or_else(|| self.filter.clone()),
+            background: preset.background.or_else(|| self.background.clone()),
+            gravity: preset.gravity.or_else(|| self.gravity.clone()),
+            focus: preset.focus.or_else(|| self.focus.clone()),
+            frame: preset.frame.or_else(|| self.frame.clone()),
+            still: preset.still.or_else(|| self.still.clone()),
+        }
+    }
+
+    pub fn is_still(&self) -> Result<bool, InfoError> {
+        let frame = match self.frame.as_deref() {
+            Some("first") => true,
+            Some(_) => return Err(InfoError::InvalidFrame),
+            None => false,
+        };
+
+        let still = match self.still.as_deref() {
+            Some("1") | Some("true") => true,
+            Some("0") | Some("false") | None => false,
+            Some(_) => return Err(InfoError::InvalidFrame),
+        };
+
+        Ok(frame || still)
+    }
+
+    pub fn get_resize_options(&self) -> Result<ResizeOptions, InfoError> {
+        let fit = match &self.fit {
+            Some(fit) => Fit::from_param(fit).ok_or(InfoError::InvalidFit)?,
+            None => Fit::Cover,
+        };
+
+        let filter = match &self.filter {
+            Some(filter) => filter_from_param(filter).ok_or(InfoError::InvalidFilter)?,
+            None => FilterType::Lanczos3,
+        };
+
+        let gravity = match (&self.focus, &self.gravity) {
+            (Some(focus), _) => Gravity::from_focus_param(focus).ok_or(InfoError::InvalidFocus)?,
+            (None, Some(gravity)) => {
+                Gravity::from_param(gravity).ok_or(InfoError::InvalidGravity)?
+            }
+            (None, None) => Gravity::Center,
+        };
+
+        let background = match &self.background {
+            Some(background) => color_from_param(background).ok_or(InfoError::InvalidBackground)?,
+            None => Rgba([u8::MAX; 4]),
+        };
+
+        Ok(ResizeOptions {
+            fit,
+            filter,
+            gravity,
+            background,
+        })
+    }
+
+    pub fn get_encode_options(&self) -> Result<EncodeOptions, InfoError> {
+        let config = &crate::ENV_CONFIG;
+
+        let quality = match self.quality {
+            Some(quality) if !(1..=100).contains(&quality) => {
+                return Err(InfoError::InvalidQuality)
+            }
+            Some(quality) => quality,
+            None => config.image_default_quality,
+        };
+
+        let compression = match &self.compression {
+            Some(compression) => {
+                PngCompression::from_param(compression).ok_or(InfoError::InvalidCompression)?
+            }
+            None => PngCompression::Default,
+        };
+
+        if matches!(self.palette, Some(colors) if !(2..=256).contains(&colors)) {
+            return Err(InfoError::InvalidPalette);
+        }
+
+        Ok(EncodeOptions {
+            quality: quality.clamp(config.image_min_quality, config.image_max_quality),
+            lossless: self.lossless.unwrap_or(false),
+            compression,
+            palette: self.palette,
+        })
+    }
+
     pub fn get_new_size(&self, width: f64, height: f64) -> Result<(u32, u32), InfoError> {
-        if let Some(w) = self.width {
+        if [self.width, self.height]
+            .iter()
+            .flatten()
+            .any(|size| !size.is_finite() || *size < 1.0)
+        {
+            return Err(InfoError::SizeTooSmall);
+        }
+
+        let (new_width, new_height) = if let Some(w) = self.width {
             if let Some(h) = self.height {
                 // If both width and height are defined, check that the new size does not exceed the given width and height
                 let new_width = if w > width { width } else { w };
@@ -40,70 +322,89 @@ impl Info {
                 let new_width = new_width.floor() as u32;
                 let new_height = new_height.floor() as u32;
 
-                Ok((new_width, new_height))
+                (new_width, new_height)
             } else if let Some(ratio) = &self.ratio {
                 // If only width is defined and ratio is defined, calculate height using cross product
-                let ratio_parts: Vec<&str> = ratio.split(':').collect();
-                if ratio_parts.len() != 2 {
-                    return Err(InfoError::InvalidRatioFormat);
-                }
-                let w_ratio: f64 = ratio_parts[0].parse().unwrap();
-                let h_ratio: f64 = ratio_parts[1].parse().unwrap();
+                let (w_ratio, h_ratio) = parse_ratio(ratio)?;
                 let new_height = w / w_ratio * h_ratio;
 
                 // Floor both
                 let new_width = w.floor() as u32;
                 let new_height = new_height.floor() as u32;
 
-                Ok((new_width, new_height))
+                (new_width, new_height)
             } else {
                 // If only width is defined, return the given width
                 let new_width = w.floor() as u32;
                 let new_height = (w / width * height).floor() as u32;
 
-                Ok((new_width, new_height))
+                (new_width, new_height)
             }
         } else if let Some(h) = self.height {
             if let Some(ratio) = &self.ratio {
                 // If only height is defined and ratio is defined, calculate width using cross product
-                let ratio_parts: Vec<&str> = ratio.split(':').collect();
-                if ratio_parts.len() != 2 {
-                    return Err(InfoError::InvalidRatioFormat);
-                }
-                let w_ratio: f64 = ratio_parts[0].parse().unwrap();
-                let h_ratio: f64 = ratio_parts[1].parse().unwrap();
+                let (w_ratio, h_ratio) = parse_ratio(ratio)?;
                 let new_width = h / h_ratio * w_ratio;
 
                 // Floor both
                 let new_width = new_width.floor() as u32;
                 let new_height = h.floor() as u32;
 
-                Ok((new_width, new_height))
+                (new_width, new_height)
             } else {
                 // If only height is defined, return the given height
                 let new_width = (h / height * width).floor() as u32;
                 let new_height = h.floor() as u32;
 
-                Ok((new_width, new_height))
+                (new_width, new_height)
             }
         } else if let Some(ratio) = &self.ratio {
-            // If only ratio is defined, calculate width and height using cross product using the initial values of width and height
-            let ratio_parts: Vec<&str> = ratio.split(':').collect();
-            if ratio_parts.len() != 2 {
-                return Err(InfoError::InvalidRatioFormat);
-            }
-            let w_ratio: f64 = ratio_parts[0].parse().unwrap();
-            let h_ratio: f64 = ratio_parts[1].parse().unwrap();
-            let new_width = (height / h_ratio * w_ratio) as u32;
-            let new_height = (width / w_ratio * h_ratio) as u32;
+            // If only ratio is defined, keep the largest size of this ratio that fits in the initial size
+            let (w_ratio, h_ratio) = parse_ratio(ratio)?;
+            let (new_width, new_height) = if width / height > w_ratio / h_ratio {
+                (height / h_ratio * w_ratio, height)
+            } else {
+                (width, width / w_ratio * h_ratio)
+            };
 
-            Ok((new_width, new_height))
+            (new_width.floor() as u32, new_height.floor() as u32)
         } else {
-            Err(InfoError::NoSizeDefined)
-        }
+            // If nothing is defined, keep the original size, scaled down to the maximum size
+            let scale = (crate::ENV_CONFIG.image_max_width as f64 / width)
+                .min(crate::ENV_CONFIG.image_max_height as f64 / height)
+                .min(1.0);
+
+            let new_width = (width * scale).floor() as u32;
+            let new_height = (height * scale).floor() as u32;
+
+            (new_width, new_height)
+        };
+
+        // A side rounded down to 0 can't be encoded
+        Ok((new_width.max(1), new_height.max(1)))
     }
 }
 
+// Round a side up to the next step of IMAGE_WIDTH_LADDER, or down to its largest step
+fn snap_to_ladder(size: f64, ladder: &[u32]) -> f64 {
+    match ladder.iter().find(|step| **step as f64 >= size) {
+        Some(step) => *step as f64,
+        None => ladder.last().map_or(size, |step| *step as f64),
+    }
+}
+
+// Parse a "width:height" ratio, both parts must be positive numbers
+fn parse_ratio(ratio: &str) -> Result<(f64, f64), InfoError> {
+    let (w_ratio, h_ratio) = ratio.split_once(':').ok_or(InfoError::InvalidRatioFormat)?;
+
+    let parse = |part: &str| match part.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(InfoError::InvalidRatioFormat),
+    };
+
+    Ok((parse(w_ratio)?, parse(h_ratio)?))
+}
+
 #[derive(Debug, Error)]
 pub enum ImageCacheError {
     #[error("Width is too large")]
@@ -114,174 +415,731 @@ pub enum ImageCacheError {
 
     #[error("Width or height is too large after ratio applied")]
     SizeTooLargeAfterRatio,
+
+    #[error("Invalid parameters: {0}")]
+    InfoError(#[from] InfoError),
+
+    #[error("Unable to fetch the image: {0}")]
+    FetchError(#[from] FetchError),
+
+    #[error("Unsupported image format")]
+    UnsupportedFormat,
+
+    #[error("Image error: {0}")]
+    ImageError(#[from] image::ImageError),
+
+    #[error("Source image is too large: {0}x{1}")]
+    SourceTooLarge(u32, u32),
+
+    #[error("Source image has too many frames: {0}")]
+    TooManyFrames(usize),
+
+    #[error("Image processing failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
 }
 
 pub async fn cache_image(
     params: &Info,
+    accept: Option<&str>,
     cache: &Cache,
+    media_cache: &MediaCache,
+    fetcher: &Fetcher,
 ) -> Result<(Vec<u8>, String), ImageCacheError> {
+    let params = &params.resolve()?;
+    let format = match &params.format {
+        Some(format) => OutputFormat::from_param(format).ok_or(InfoError::InvalidFormat)?,
+        None => OutputFormat::from_accept(accept),
+    };
+    let options = params.get_encode_options()?;
+    let resize_options = params.get_resize_options()?;
+    let still = params.is_still()?;
+
     let file_name = &format!(
-        "{}-{}-{}-{}",
+        "{}-{}-{}-{}-{}-{}-{}{}",
         params.url,
-        params.ratio.clone().unwrap_or(String::new()),
+        params.ratio.clone().unwrap_or_default(),
         params.width.unwrap_or(0.0),
-        params.height.unwrap_or(0.0)
+        params.height.unwrap_or(0.0),
+        format.name(),
+        options.cache_key(),
+        resize_options.cache_key(),
+        if still { "-still" } else { "" }
     );
-    let mut image_cache = get_media_cache(file_name, cache).await;
 
-    // Check if the body is an image
+    if let Some(image_cache) = media_cache.get(file_name).await {
+        return Ok(image_cache);
+    }
 
-    if image_cache.is_none() {
-        // Fetch the url
-        let client = reqwest::Client::new();
-        let response = client.get(&params.url).send().await.unwrap();
-        let body_response = response.bytes().await.unwrap();
+    // Fetch the url
+    let body_response = fetcher.get_bytes(&params.url).await?;
 
-        // First size check
-        if params.width.is_some()
-            && params.width.unwrap() > crate::ENV_CONFIG.image_max_width as f64
-        {
-            return Err(ImageCacheError::WidthTooLarge);
+    // First size check
+    if params.width.is_some() && params.width.unwrap() > crate::ENV_CONFIG.image_max_width as f64 {
+        return Err(ImageCacheError::WidthTooLarge);
+    }
+
+    if params.height.is_some() && params.height.unwrap() > crate::ENV_CONFIG.image_max_height as f64
+    {
+        return Err(ImageCacheError::HeightTooLarge);
+    }
+
+    // Decoding and encoding an animation can take seconds, keep them off the async workers
+    let (new_content, format) = {
+        let params = params.clone();
+        tokio::task::spawn_blocking(move || {
+            encode_image(
+                &body_response,
+                &params,
+                format,
+                still,
+                &options,
+                &resize_options,
+            )
+        })
+        .await??
+    };
+    let mime_type = format.mime_type();
+
+    media_cache
+        .set(
+            file_name,
+            &new_content,
+            mime_type,
+            crate::ENV_CONFIG.cache_ttl_images,
+        )
+        .await;
+
+    if let Err(err) = cache
+        .set_str(
+            file_name,
+            &chrono::Utc::now().timestamp().to_string(),
+            crate::ENV_CONFIG.cache_ttl_images,
+        )
+        .await
+    {
+        println!("Cache error: {err}");
+    }
+
+    // Serve the fresh image even if the media cache is unavailable
+    Ok((new_content, mime_type.to_string()))
+}
+
+/// Decode, resize and encode a source to the requested format, which is adapted to the source
+fn encode_image(
+    body_response: &[u8],
+    params: &Info,
+    format: OutputFormat,
+    still: bool,
+    options: &EncodeOptions,
+    resize_options: &ResizeOptions,
+) -> Result<(Vec<u8>, OutputFormat), ImageCacheError> {
+    let (source, image) = load_source(body_response)?;
+
+    let (new_width, new_height) =
+        params.get_new_size(image.width() as f64, image.height() as f64)?;
+
+    // Second size check
+    if new_width > crate::ENV_CONFIG.image_max_width as u32
+        || new_height > crate::ENV_CONFIG.image_max_height as u32
+    {
+        return Err(ImageCacheError::SizeTooLargeAfterRatio);
+    }
+
+    // A still only uses the first frame, which is the one already decoded
+    let frames = match source {
+        SourceFormat::Image(_) if !still => animation::info(body_response).frames,
+        _ => 1,
+    };
+    let animated = frames > 1;
+
+    // Every frame of an animation is decoded into a full RGBA canvas
+    if animated
+        && (frames > crate::ENV_CONFIG.image_max_source_frames
+            || frames as u64 * image.width() as u64 * image.height() as u64 * 4
+                > crate::ENV_CONFIG.image_max_decode_bytes)
+    {
+        return Err(ImageCacheError::TooManyFrames(frames));
+    }
+
+    // Animation frames are resized one by one by their encoder
+    let image = match animated {
+        true => image,
+        false => resize(&image, new_width, new_height, resize_options),
+    };
+
+    // The outputs are encoded from the pixels only, none of the EXIF, GPS, XMP, IPTC,
+    // ICC or comments of the source are copied
+    let format = format.for_source(animated, &image);
+    let content = match format {
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => crate::systems::images::avif::run(&image, options)?,
+        #[cfg(not(feature = "avif"))]
+        OutputFormat::Avif => unreachable!("AVIF is only negotiated with the avif feature"),
+        OutputFormat::WebP if animated => {
+            // Every resized frame is kept in memory until the end of the encoding
+            if frames as u64 * new_width as u64 * new_height as u64 * 4
+                > crate::ENV_CONFIG.image_max_decode_bytes
+            {
+                return Err(ImageCacheError::TooManyFrames(frames));
+            }
+
+            crate::systems::images::webp::run_animated(
+                body_response,
+                new_width,
+                new_height,
+                resize_options,
+                options,
+            )?
         }
+        OutputFormat::WebP => crate::systems::images::webp::run(&image, options)?,
+        OutputFormat::Jpeg => crate::systems::images::jpg::run(&image, options)?,
+        OutputFormat::Png => crate::systems::images::png::run(&image, options)?,
+        OutputFormat::Gif if animated => {
+            crate::systems::images::gif::run(body_response, new_width, new_height, resize_options)?
+        }
+        OutputFormat::Gif => crate::systems::images::gif::run_still(&image)?,
+        #[cfg(feature = "webm")]
+        OutputFormat::WebM => crate::systems::images::webm::run(
+            body_response,
+            new_width,
+            new_height,
+            resize_options,
+            options,
+        )?,
+        #[cfg(not(feature = "webm"))]
+        OutputFormat::WebM => unreachable!("WebM is only negotiated with the webm feature"),
+    };
+
+    Ok((content, format))
+}
+
+/// Compute the placeholder of an image, returned as JSON with the size of the image
+pub async fn cache_placeholder(
+    params: &PlaceholderInfo,
+    cache: &Cache,
+    fetcher: &Fetcher,
+) -> Result<String, ImageCacheError> {
+    let placeholder = match &params.hash {
+        Some(hash) => Placeholder::from_param(hash).ok_or(InfoError::InvalidPlaceholder)?,
+        None => Placeholder::BlurHash,
+    };
+
+    let cache_key = format!("placeholder:{}:{}", placeholder.name(), params.url);
+    if let Ok(placeholder) = cache.get_str(&cache_key).await {
+        return Ok(placeholder);
+    }
+
+    let body_response = fetcher.get_bytes(&params.url).await?;
+    let placeholder = tokio::task::spawn_blocking(move || {
+        let (_, image) = load_source(&body_response)?;
+
+        Ok::<_, ImageCacheError>(
+            json!({
+                "type": placeholder.name(),
+                "hash": placeholder.encode(&image),
+                "width": image.width(),
+                "height": image.height(),
+            })
+            .to_string(),
+        )
+    })
+    .await??;
 
-        if params.height.is_some()
-            && params.height.unwrap() > crate::ENV_CONFIG.image_max_height as f64
+    if let Err(err) = cache
+        .set_str(&cache_key, &placeholder, crate::ENV_CONFIG.cache_ttl_images)
+        .await
+    {
+        println!("Cache error: {err}");
+    }
+
+    Ok(placeholder)
+}
+
+/// Describe an image, returned as JSON
+pub async fn cache_image_info(
+    params: &MetadataInfo,
+    cache: &Cache,
+    fetcher: &Fetcher,
+) -> Result<String, ImageCacheError> {
+    let cache_key = format!("info:{}", params.url);
+    if let Ok(info) = cache.get_str(&cache_key).await {
+        return Ok(info);
+    }
+
+    let body_response = fetcher.get_bytes(&params.url).await?;
+    let info = tokio::task::spawn_blocking(move || {
+        let (source, image) = load_source(&body_response)?;
+        let animation = match source {
+            SourceFormat::Image(_) => animation::info(&body_response),
+            _ => animation::AnimationInfo::still(),
+        };
+
+        Ok::<_, ImageCacheError>(
+            json!({
+                "width": image.width(),
+                "height": image.height(),
+                "mime_type": source.mime_type(),
+                "bytes": body_response.len(),
+                "frames": animation.frames.max(1),
+                "duration_ms": animation.duration_ms,
+                "dominant_color": color::dominant_color(&image).map(|color| format!("#{}", hex::encode(color))),
+                "sha256": hex::encode(Sha256::digest(&body_response)),
+            })
+            .to_string(),
+        )
+    })
+    .await??;
+
+    if let Err(err) = cache
+        .set_str(&cache_key, &info, crate::ENV_CONFIG.cache_ttl_image_info)
+        .await
+    {
+        println!("Cache error: {err}");
+    }
+
+    Ok(info)
+}
+
+/// Detect the format of a source and decode it, upright
+fn load_source(body: &[u8]) -> Result<(SourceFormat, DynamicImage), ImageCacheError> {
+    let source = SourceFormat::detect(body).ok_or(ImageCacheError::UnsupportedFormat)?;
+
+    // Phone photos are stored sideways with an EXIF orientation, which is not kept in the output
+    let image = decode_source(body, source)?;
+    let image = match source {
+        SourceFormat::Image(_) => orientation::apply(image, orientation::read(body)),
+        _ => image,
+    };
+
+    Ok((source, image))
+}
+
+/// Decode an untrusted image (the first frame of the animations), its size is read first
+/// so that a small file declaring a huge canvas is rejected before any pixel is allocated
+fn decode_source(body: &[u8], source: SourceFormat) -> Result<DynamicImage, ImageCacheError> {
+    let config = &crate::ENV_CONFIG;
+
+    let check_size = |width: u32, height: u32| {
+        if width > config.image_max_source_width
+            || height > config.image_max_source_height
+            || width as u64 * height as u64 > config.image_max_source_pixels
         {
-            return Err(ImageCacheError::HeightTooLarge);
+            return Err(ImageCacheError::SourceTooLarge(width, height));
         }
 
-        let image = image::load_from_memory(&body_response).unwrap();
+        Ok(())
+    };
 
-        let (new_width, new_height) = params
-            .get_new_size(image.width() as f64, image.height() as f64)
-            .unwrap();
+    let format = match source {
+        SourceFormat::Image(format) => format,
+        SourceFormat::Svg => return svg::decode(body, check_size),
+        #[cfg(feature = "heif")]
+        SourceFormat::Heic | SourceFormat::Avif => {
+            return crate::systems::images::heif::decode(body, check_size)
+        }
+        #[cfg(not(feature = "heif"))]
+        SourceFormat::Heic | SourceFormat::Avif => return Err(ImageCacheError::UnsupportedFormat),
+    };
 
-        // Second size check
-        if new_width > crate::ENV_CONFIG.image_max_width as u32
-            || new_height > crate::ENV_CONFIG.image_max_height as u32
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(config.image_max_source_width);
+    limits.max_image_height = Some(config.image_max_source_height);
+    limits.max_alloc = Some(config.image_max_decode_bytes);
+
+    let mut reader = image::io::Reader::with_format(Cursor::new(body), format);
+    reader.limits(limits.clone());
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|_| ImageCacheError::UnsupportedFormat)?;
+    check_size(width, height)?;
+
+    let frames = animation::info(body).frames;
+    if frames > 1 {
+        // libwebp decodes every frame of a WebP animation at once, even for its first frame
+        if format == ImageFormat::WebP
+            && frames as u64 * width as u64 * height as u64 * 4 > config.image_max_decode_bytes
         {
-            return Err(ImageCacheError::SizeTooLargeAfterRatio);
+            return Err(ImageCacheError::TooManyFrames(frames));
         }
 
-        // Determine the image format
-        let type_image = image::guess_format(&body_response).unwrap();
+        return Ok(animation::first_frame(body)?);
+    }
 
-        match type_image {
-            ImageFormat::Png => {
-                let new_png = crate::systems::images::png::run(&image, new_width, new_height);
+    let mut reader = image::io::Reader::with_format(Cursor::new(body), format);
+    reader.limits(limits);
 
-                set_media_cache(file_name, &new_png, cache).await;
+    Ok(reader.decode()?)
+}
 
-                cache
-                    .set_str(
-                        &format!("{file_name}+ext"),
-                        "image/png",
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                cache
-                    .set_str(
-                        file_name,
-                        &chrono::Utc::now().timestamp().to_string(),
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
+    fn info(width: Option<f64>, height: Option<f64>, ratio: Option<&str>) -> Info {
+        Info {
+            url: "https://example.com/image.png".to_string(),
+            width,
+            height,
+            ratio: ratio.map(str::to_string),
+            ..Info::default()
+        }
+    }
 
-                image_cache = get_media_cache(file_name, cache).await;
+    #[test]
+    fn new_size_is_never_zero() {
+        // 1000x1 scaled to a width of 1
+        assert_eq!(
+            info(Some(1.0), None, None).get_new_size(1000.0, 1.0),
+            Ok((1, 1))
+        );
+        // 1x1000 scaled to a height of 1
+        assert_eq!(
+            info(None, Some(1.0), None).get_new_size(1.0, 1000.0),
+            Ok((1, 1))
+        );
+        // 1x1 cropped to 1:3, the closest size is the source itself
+        assert_eq!(
+            info(None, None, Some("1:3")).get_new_size(1.0, 1.0),
+            Ok((1, 1))
+        );
+        assert_eq!(
+            info(None, None, Some("3:1")).get_new_size(1.0, 1.0),
+            Ok((1, 1))
+        );
+        assert_eq!(
+            info(Some(1.0), None, Some("1:3")).get_new_size(10.0, 10.0),
+            Ok((1, 3))
+        );
+        assert_eq!(
+            info(Some(1.0), None, Some("3:1")).get_new_size(10.0, 10.0),
+            Ok((1, 1))
+        );
+        assert_eq!(
+            info(None, Some(1.0), Some("1:3")).get_new_size(10.0, 10.0),
+            Ok((1, 1))
+        );
+    }
 
-                return Ok(image_cache.unwrap());
-            }
-            ImageFormat::Jpeg => {
-                let new_jpeg = crate::systems::images::jpg::run(&image, new_width, new_height);
-
-                set_media_cache(file_name, &new_jpeg, cache).await;
-
-                cache
-                    .set_str(
-                        &format!("{file_name}+ext"),
-                        "image/jpeg",
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                cache
-                    .set_str(
-                        file_name,
-                        &chrono::Utc::now().timestamp().to_string(),
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                image_cache = get_media_cache(file_name, cache).await;
-
-                return Ok(image_cache.unwrap());
-            }
-            ImageFormat::Gif => {
-                let new_gif = crate::systems::images::gif::run(
-                    &body_response.try_into().unwrap(),
-                    new_width,
-                    new_height,
-                );
-
-                set_media_cache(file_name, &new_gif, cache).await;
-
-                cache
-                    .set_str(
-                        &format!("{file_name}+ext"),
-                        "image/gif",
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                cache
-                    .set_str(
-                        file_name,
-                        &chrono::Utc::now().timestamp().to_string(),
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                image_cache = get_media_cache(file_name, cache).await;
-
-                return Ok(image_cache.unwrap());
-            }
-            ImageFormat::WebP => {
-                let new_webp = crate::systems::images::webp::run(&image, new_width, new_height);
-
-                set_media_cache(file_name, &new_webp, cache).await;
-
-                cache
-                    .set_str(
-                        &format!("{file_name}+ext"),
-                        "image/webp",
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                cache
-                    .set_str(
-                        file_name,
-                        &chrono::Utc::now().timestamp().to_string(),
-                        crate::ENV_CONFIG.cache_ttl_images,
-                    )
-                    .await
-                    .unwrap();
-
-                image_cache = get_media_cache(file_name, cache).await;
-
-                return Ok(image_cache.unwrap());
+    #[test]
+    fn new_size_keeps_the_ratio() {
+        assert_eq!(
+            info(Some(100.0), None, None).get_new_size(400.0, 300.0),
+            Ok((100, 75))
+        );
+        assert_eq!(
+            info(None, Some(150.0), None).get_new_size(400.0, 300.0),
+            Ok((200, 150))
+        );
+        assert_eq!(
+            info(Some(160.0), None, Some("16:9")).get_new_size(400.0, 300.0),
+            Ok((160, 90))
+        );
+        assert_eq!(
+            info(Some(500.0), Some(100.0), None).get_new_size(400.0, 300.0),
+            Ok((400, 100))
+        );
+    }
+
+    #[test]
+    fn new_size_crops_to_the_ratio() {
+        // Cropped on the width
+        assert_eq!(
+            info(None, None, Some("16:9")).get_new_size(400.0, 300.0),
+            Ok((400, 225))
+        );
+        // Cropped on the height
+        assert_eq!(
+            info(None, None, Some("16:9")).get_new_size(800.0, 300.0),
+            Ok((533, 300))
+        );
+        assert_eq!(
+            info(None, None, Some("1:1")).get_new_size(1000.0, 100.0),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            info(None, None, Some("1:1")).get_new_size(100.0, 1000.0),
+            Ok((100, 100))
+        );
+        // Already at the ratio
+        assert_eq!(
+            info(None, None, Some("4:3")).get_new_size(400.0, 300.0),
+            Ok((400, 300))
+        );
+    }
+
+    #[test]
+    fn ratio_must_be_two_positive_numbers() {
+        assert_eq!(parse_ratio("16:9"), Ok((16.0, 9.0)));
+        assert_eq!(parse_ratio(" 1.5 : 1 "), Ok((1.5, 1.0)));
+
+        for ratio in ["16:0", "0:9", "-16:9", "a:b", "16", "16:9:1", "inf:1", ""] {
+            assert_eq!(parse_ratio(ratio), Err(InfoError::InvalidRatioFormat));
+            assert_eq!(
+                info(None, None, Some(ratio)).get_new_size(400.0, 300.0),
+                Err(InfoError::InvalidRatioFormat)
+            );
+        }
+    }
+
+    #[test]
+    fn new_size_refuses_invalid_sizes() {
+        for size in [0.0, 0.5, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                info(Some(size), None, None).get_new_size(100.0, 100.0),
+                Err(InfoError::SizeTooSmall)
+            );
+        }
+    }
+
+    fn encode_options(lossless: bool, palette: Option<u16>) -> EncodeOptions {
+        EncodeOptions {
+            quality: 80,
+            lossless,
+            compression: PngCompression::Default,
+            palette,
+        }
+    }
+
+    #[test]
+    fn outputs_have_no_metadata() {
+        use crate::systems::images::{jpg, png, webp};
+
+        // Stored as 40x20, displayed as 20x40
+        let content = orientation::tests::jpeg_with_exif(40, 20);
+        let (source, image) = load_source(&content).unwrap();
+        assert_eq!(source, SourceFormat::Image(ImageFormat::Jpeg));
+        assert_eq!((image.width(), image.height()), (20, 40));
+
+        let outputs = [
+            ("jpeg", jpg::run(&image, &encode_options(false, None))),
+            ("png", png::run(&image, &encode_options(false, None))),
+            (
+                "png palette",
+                png::run(&image, &encode_options(false, Some(16))),
+            ),
+            ("webp", webp::run(&image, &encode_options(false, None))),
+            (
+                "webp lossless",
+                webp::run(&image, &encode_options(true, None)),
+            ),
+            #[cfg(feature = "avif")]
+            (
+                "avif",
+                crate::systems::images::avif::run(&image, &encode_options(false, None)),
+            ),
+        ];
+
+        for (name, output) in outputs {
+            let output = output.unwrap();
+
+            assert!(
+                exif::Reader::new()
+                    .read_from_container(&mut Cursor::new(&output))
+                    .is_err(),
+                "{name} has EXIF"
+            );
+            if name != "avif" {
+                let decoded = image::load_from_memory(&output).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (20, 40), "{name}");
             }
-            _ => todo!("type_image: {:#?} not supported yet", type_image),
         }
     }
 
-    Ok(image_cache.unwrap())
+    #[test]
+    fn degenerate_sizes_can_be_encoded() {
+        let options = encode_options(false, None);
+
+        for (source, info) in [
+            ((1000, 1), info(Some(1.0), None, None)),
+            ((1, 1), info(None, None, Some("1:3"))),
+        ] {
+            let image = DynamicImage::new_rgb8(source.0, source.1);
+            let (new_width, new_height) = info
+                .get_new_size(image.width() as f64, image.height() as f64)
+                .unwrap();
+            let image = resize(
+                &image,
+                new_width,
+                new_height,
+                &info.get_resize_options().unwrap(),
+            );
+
+            assert!(crate::systems::images::webp::run(&image, &options).is_ok());
+            assert!(crate::systems::images::jpg::run(&image, &options).is_ok());
+            assert!(crate::systems::images::png::run(&image, &options).is_ok());
+        }
+    }
+
+    #[test]
+    fn encodes_animations() {
+        use image::codecs::gif::Repeat;
+
+        let content = animation::tests::gif(&[100, 200], Repeat::Infinite);
+        let info = info(Some(8.0), None, None);
+        let encode = |format, still| {
+            encode_image(
+                &content,
+                &info,
+                format,
+                still,
+                &encode_options(false, None),
+                &info.get_resize_options().unwrap(),
+            )
+            .unwrap()
+        };
+
+        let (webp, format) = encode(OutputFormat::WebP, false);
+        assert_eq!(format, OutputFormat::WebP);
+        assert_eq!(animation::info(&webp).frames, 2);
+
+        // Animations can't be sent as JPEG
+        let (gif, format) = encode(OutputFormat::Jpeg, false);
+        assert_eq!(format, OutputFormat::Gif);
+        let gif_info = animation::info(&gif);
+        assert_eq!(gif_info.frames, 2);
+        assert_eq!(gif_info.duration_ms, 300);
+
+        // Only the first frame of a still
+        let (jpeg, format) = encode(OutputFormat::Jpeg, true);
+        assert_eq!(format, OutputFormat::Jpeg);
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (8, 8));
+        let [r, g, b, _] = image.to_rgba8().get_pixel(4, 4).0;
+        assert!(r > 200 && g < 50 && b < 50);
+    }
+
+    #[test]
+    fn encodes_stills_as_gif() {
+        let content = crate::systems::images::png::run(
+            &DynamicImage::new_rgb8(20, 10),
+            &encode_options(false, None),
+        )
+        .unwrap();
+        let info = info(None, None, None);
+
+        let (gif, format) = encode_image(
+            &content,
+            &info,
+            OutputFormat::from_param("gif").unwrap(),
+            false,
+            &encode_options(false, None),
+            &info.get_resize_options().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(format, OutputFormat::Gif);
+        assert_eq!(image::guess_format(&gif).unwrap(), ImageFormat::Gif);
+        assert_eq!(animation::info(&gif).frames, 1);
+        let image = image::load_from_memory(&gif).unwrap();
+        assert_eq!((image.width(), image.height()), (20, 10));
+    }
+
+    const LADDER: [u32; 3] = [160, 320, 640];
+
+    fn presets() -> HashMap<String, Preset> {
+        [
+            ("avatar_64", "width=64&ratio=1:1&format=webp"),
+            ("banner", "width=1200&ratio=3:1&quality=60"),
+        ]
+        .into_iter()
+        .map(|(name, parameters)| {
+            let preset = actix_web::web::Query::<Preset>::from_query(parameters).unwrap();
+            (name.to_string(), preset.into_inner())
+        })
+        .collect()
+    }
+
+    fn resolve(info: &Info) -> Result<(Option<f64>, Option<f64>), InfoError> {
+        let info = info.resolve_with(&presets(), true, &LADDER)?;
+        Ok((info.width, info.height))
+    }
+
+    #[test]
+    fn resolves_the_presets() {
+        let request = Info {
+            preset: Some("avatar_64".to_string()),
+            format: Some("png".to_string()),
+            quality: Some(90),
+            ..info(None, None, None)
+        };
+        let resolved = request.resolve_with(&presets(), false, &LADDER).unwrap();
+
+        // The preset replaces the parameters of the request, and its size is not snapped
+        assert_eq!(resolved.preset, None);
+        assert_eq!((resolved.width, resolved.height), (Some(64.0), None));
+        assert_eq!(resolved.ratio.as_deref(), Some("1:1"));
+        assert_eq!(resolved.format.as_deref(), Some("webp"));
+        assert_eq!(resolved.quality, Some(90));
+        assert_eq!(resolved.url, request.url);
+    }
+
+    #[test]
+    fn refuses_unknown_presets_and_sizes() {
+        let preset = |name: &str, width| Info {
+            preset: Some(name.to_string()),
+            ..info(width, None, None)
+        };
+
+        assert!(matches!(
+            preset("unknown", None).resolve_with(&presets(), true, &LADDER),
+            Err(InfoError::InvalidPreset)
+        ));
+        assert!(matches!(
+            preset("banner", Some(100.0)).resolve_with(&presets(), true, &LADDER),
+            Err(InfoError::PresetWithSize)
+        ));
+        for info in [
+            info(Some(100.0), None, None),
+            info(None, Some(100.0), None),
+            info(None, None, Some("1:1")),
+        ] {
+            assert!(matches!(
+                info.resolve_with(&presets(), false, &LADDER),
+                Err(InfoError::CustomSizeForbidden)
+            ));
+        }
+        assert!(info(None, None, None)
+            .resolve_with(&presets(), false, &LADDER)
+            .is_ok());
+    }
+
+    #[test]
+    fn snaps_the_sizes_to_the_ladder() {
+        // Up to the next step, down to the largest one
+        assert_eq!(
+            resolve(&info(Some(100.0), None, None)),
+            Ok((Some(160.0), None))
+        );
+        assert_eq!(
+            resolve(&info(Some(320.0), None, None)),
+            Ok((Some(320.0), None))
+        );
+        assert_eq!(
+            resolve(&info(Some(321.0), None, None)),
+            Ok((Some(640.0), None))
+        );
+        assert_eq!(
+            resolve(&info(Some(5000.0), None, None)),
+            Ok((Some(640.0), None))
+        );
+        assert_eq!(
+            resolve(&info(None, Some(200.0), None)),
+            Ok((None, Some(320.0)))
+        );
+
+        // The height keeps the requested aspect ratio
+        assert_eq!(
+            resolve(&info(Some(300.0), Some(200.0), None)),
+            Ok((Some(320.0), Some(213.0)))
+        );
+        assert_eq!(
+            resolve(&info(Some(320.0), Some(199.5), None)),
+            Ok((Some(320.0), Some(199.5)))
+        );
+
+        // Without a ladder the sizes are kept
+        let info = info(Some(300.0), Some(200.0), None)
+            .resolve_with(&presets(), true, &[])
+            .unwrap();
+        assert_eq!((info.width, info.height), (Some(300.0), Some(200.0)));
+    }
 }
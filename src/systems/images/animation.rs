@@ -0,0 +1,464 @@
+use std::io::Cursor;
+
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder},
+    error::{DecodingError, ImageFormatHint},
+    AnimationDecoder, Delay, DynamicImage, Frame, ImageError, ImageFormat, ImageResult, RgbaImage,
+};
+
+use super::resize::{resize, ResizeOptions};
+
+/// Structure of an animation (GIF, APNG or animated WebP), read without decoding any pixel
+pub struct AnimationInfo {
+    pub frames: usize,
+    // Repetitions after the first play like in GIF, 0 means forever and None means played once
+    pub loop_count: Option<u16>,
+    // Duration of one play as displayed by the browsers, 0 for a still image
+    pub duration_ms: u64,
+}
+
+impl AnimationInfo {
+    pub fn still() -> Self {
+        Self {
+            frames: 1,
+            loop_count: None,
+            duration_ms: 0,
+        }
+    }
+}
+
+pub fn info(content: &[u8]) -> AnimationInfo {
+    match image::guess_format(content) {
+        Ok(ImageFormat::Gif) => gif_info(content),
+        Ok(ImageFormat::Png) => apng_info(content),
+        Ok(ImageFormat::WebP) => webp_info(content),
+        _ => AnimationInfo::still(),
+    }
+}
+
+/// Decode and resize the frames of an animation one by one, each one as a full canvas
+///
+/// The decoders composite each frame over the previous ones according to its disposal method.
+pub fn for_each_frame(
+    content: &[u8],
+    new_width: u32,
+    new_height: u32,
+    options: &ResizeOptions,
+    mut callback: impl FnMut(RgbaImage, Delay) -> ImageResult<()>,
+) -> ImageResult<usize> {
+    let (format, frames) = frames(content)?;
+
+    // Crop every frame the same way
+    let mut options = *options;
+    let mut frames_count = 0;
+
+    for frame in frames {
+        let (image, delay) = frame?;
+        let image = DynamicImage::ImageRgba8(image);
+
+        if frames_count == 0 {
+            options = options.resolve_attention(&image, new_width, new_height);
+        }
+        frames_count += 1;
+
+        callback(
+            resize(&image, new_width, new_height, &options).into_rgba8(),
+            delay,
+        )?;
+    }
+
+    if frames_count == 0 {
+        return Err(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(format),
+            "Animation without any frame",
+        )));
+    }
+
+    Ok(frames_count)
+}
+
+/// First frame of an animation, as a full canvas
+///
+/// The still decoder of the image crate can't be used: it panics on some animated WebP
+/// and it returns the default image of an APNG, which may not be part of the animation.
+pub fn first_frame(content: &[u8]) -> ImageResult<DynamicImage> {
+    let (format, mut frames) = frames(content)?;
+
+    match frames.next() {
+        Some(frame) => Ok(DynamicImage::ImageRgba8(frame?.0)),
+        None => Err(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(format),
+            "Animation without any frame",
+        ))),
+    }
+}
+
+type FrameIterator<'a> = Box<dyn Iterator<Item = ImageResult<(RgbaImage, Delay)>> + 'a>;
+
+fn frames(content: &[u8]) -> ImageResult<(ImageFormat, FrameIterator<'_>)> {
+    let format = image::guess_format(content)?;
+    let frames: FrameIterator = match format {
+        ImageFormat::Png => Box::new(
+            PngDecoder::new(Cursor::new(content))?
+                .apng()
+                .into_frames()
+                .map(|frame| frame.map(into_parts)),
+        ),
+        // The WebP decoder of the image crate panics on the lossy frames without alpha
+        ImageFormat::WebP => Box::new(webp_frames(content)?.into_iter().map(Ok)),
+        _ => Box::new(
+            GifDecoder::new(Cursor::new(content))?
+                .into_frames()
+                .map(|frame| frame.map(into_parts)),
+        ),
+    };
+
+    Ok((format, frames))
+}
+
+fn into_parts(frame: Frame) -> (RgbaImage, Delay) {
+    let delay = frame.delay();
+    (frame.into_buffer(), delay)
+}
+
+// libwebp decodes every frame of the animation at once
+fn webp_frames(content: &[u8]) -> ImageResult<Vec<(RgbaImage, Delay)>> {
+    let decoding_error = |message: &str| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::WebP),
+            message.to_string(),
+        ))
+    };
+
+    let animation = webp::AnimDecoder::new(content)
+        .decode()
+        .map_err(|err| decoding_error(&err))?;
+
+    // The timestamp of a frame is the end of its display
+    let mut previous_timestamp = 0;
+    animation
+        .into_iter()
+        .map(|frame| {
+            let delay = (frame.get_time_ms() - previous_timestamp).max(0) as u32;
+            previous_timestamp = frame.get_time_ms();
+
+            let image =
+                RgbaImage::from_raw(frame.width(), frame.height(), frame.get_image().to_vec())
+                    .ok_or_else(|| decoding_error("invalid frame size"))?;
+            Ok((image, Delay::from_numer_denom_ms(delay, 1)))
+        })
+        .collect()
+}
+
+/// Delay of a frame as displayed by the browsers, which slow down the frames of 10ms or less
+pub fn displayed_delay_ms(delay: Delay) -> u32 {
+    let (numerator, denominator) = delay.numer_denom_ms();
+    let delay = numerator / denominator.max(1);
+
+    if delay <= 10 {
+        100
+    } else {
+        delay
+    }
+}
+
+// APNG and WebP count the plays, 0 meaning forever
+fn loop_count_from_plays(plays: u32) -> Option<u16> {
+    match plays {
+        0 => Some(0),
+        1 => None,
+        plays => Some((plays - 1).min(u16::MAX as u32) as u16),
+    }
+}
+
+fn gif_info(content: &[u8]) -> AnimationInfo {
+    let mut info = AnimationInfo {
+        frames: 0,
+        loop_count: None,
+        duration_ms: 0,
+    };
+    // Delay of the next image, in hundredths of a second
+    let mut delay = 0;
+
+    if content.len() < 13 {
+        return info;
+    }
+
+    // Header, logical screen descriptor and global color table
+    let mut pos = 13 + color_table_size(content[10]);
+
+    while pos < content.len() {
+        match content[pos] {
+            // Application extension: introducer, label, identifier block, sub-blocks
+            0x21 if content.get(pos + 1) == Some(&0xFF) => {
+                let identifier = content.get(pos + 3..pos + 14);
+                if identifier == Some(b"NETSCAPE2.0") || identifier == Some(b"ANIMEXTS1.0") {
+                    // Looping sub-block: size (3), id (1), count (u16 little endian)
+                    if let Some([3, 1, low, high]) = content.get(pos + 14..pos + 18) {
+                        info.loop_count = Some(u16::from_le_bytes([*low, *high]));
+                    }
+                }
+                pos = skip_sub_blocks(content, pos + 2);
+            }
+            // Graphic control extension: introducer, label, size (4), flags, delay (u16 little endian)
+            0x21 if content.get(pos + 1) == Some(&0xF9) => {
+                if let Some([low, high]) = content.get(pos + 4..pos + 6) {
+                    delay = u16::from_le_bytes([*low, *high]) as u32;
+                }
+                pos = skip_sub_blocks(content, pos + 2);
+            }
+            // Other extensions: introducer, label, sub-blocks
+            0x21 => pos = skip_sub_blocks(content, pos + 2),
+            // Image: descriptor, local color table, LZW minimum code size, sub-blocks
+            0x2C => {
+                info.frames += 1;
+                info.duration_ms +=
+                    displayed_delay_ms(Delay::from_numer_denom_ms(delay * 10, 1)) as u64;
+                delay = 0;
+                let flags = match content.get(pos + 9) {
+                    Some(flags) => *flags,
+                    None => break,
+                };
+                pos = skip_sub_blocks(content, pos + 10 + color_table_size(flags) + 1);
+            }
+            // Trailer or garbage
+            _ => break,
+        }
+    }
+
+    // The delay of a still image is meaningless
+    if info.frames <= 1 {
+        info.duration_ms = 0;
+    }
+
+    info
+}
+
+fn color_table_size(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 * (1 << ((flags & 0x07) + 1))
+    } else {
+        0
+    }
+}
+
+fn skip_sub_blocks(content: &[u8], mut pos: usize) -> usize {
+    while let Some(size) = content.get(pos) {
+        pos += 1 + *size as usize;
+        if *size == 0 {
+            break;
+        }
+    }
+
+    pos
+}
+
+fn apng_info(content: &[u8]) -> AnimationInfo {
+    let mut info = AnimationInfo {
+        frames: 1,
+        loop_count: None,
+        duration_ms: 0,
+    };
+    let mut animated = false;
+
+    // Signature then chunks of size (u32 big endian), type, data and CRC
+    let mut pos = 8;
+    while let (Some(size), Some(chunk_type)) =
+        (content.get(pos..pos + 4), content.get(pos + 4..pos + 8))
+    {
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let data = content.get(pos + 8..pos + 8 + size).unwrap_or_default();
+
+        match chunk_type {
+            // acTL: number of frames and number of plays, before the image data
+            b"acTL" if data.len() >= 8 => {
+                animated = true;
+                info.frames = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                info.loop_count =
+                    loop_count_from_plays(u32::from_be_bytes([data[4], data[5], data[6], data[7]]));
+            }
+            // fcTL: sequence, size, offset, then the delay as a fraction of a second
+            b"fcTL" if animated && data.len() >= 24 => {
+                let numerator = u16::from_be_bytes([data[20], data[21]]) as u32;
+                let denominator = match u16::from_be_bytes([data[22], data[23]]) {
+                    0 => 100,
+                    denominator => denominator as u32,
+                };
+                info.duration_ms +=
+                    displayed_delay_ms(Delay::from_numer_denom_ms(numerator * 1000, denominator))
+                        as u64;
+            }
+            b"IDAT" if !animated => break,
+            b"IEND" => break,
+            _ => {}
+        }
+
+        pos += 12 + size;
+    }
+
+    info
+}
+
+fn webp_info(content: &[u8]) -> AnimationInfo {
+    let mut info = AnimationInfo {
+        frames: 0,
+        loop_count: None,
+        duration_ms: 0,
+    };
+
+    // RIFF header then chunks of fourcc, size (u32 little endian) and padded payload
+    let mut pos = 12;
+    while let (Some(fourcc), Some(size)) =
+        (content.get(pos..pos + 4), content.get(pos + 4..pos + 8))
+    {
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+
+        match fourcc {
+            // ANIM: background color then number of plays (u16 little endian)
+            b"ANIM" => {
+                if let Some([low, high]) = content.get(pos + 12..pos + 14) {
+                    info.loop_count =
+                        loop_count_from_plays(u16::from_le_bytes([*low, *high]) as u32);
+                }
+            }
+            // ANMF: offset and size of the frame on 3 bytes each, then its duration
+            b"ANMF" => {
+                info.frames += 1;
+                if let Some(duration) = content.get(pos + 20..pos + 23) {
+                    let duration = u32::from_le_bytes([duration[0], duration[1], duration[2], 0]);
+                    info.duration_ms +=
+                        displayed_delay_ms(Delay::from_numer_denom_ms(duration, 1)) as u64;
+                }
+            }
+            _ => {}
+        }
+
+        pos += 8 + size + size % 2;
+    }
+
+    // A still WebP has no frame chunk
+    info.frames = info.frames.max(1);
+    info
+}
+
+#[cfg(test)]
+pub mod tests {
+    use image::{
+        codecs::gif::{GifEncoder, Repeat},
+        imageops::FilterType,
+        Rgba,
+    };
+
+    use super::*;
+    use crate::systems::images::{
+        resize::{Fit, Gravity},
+        EncodeOptions, PngCompression,
+    };
+
+    /// 16x16 GIF with one solid frame per delay, red then green then blue
+    pub fn gif(delays_ms: &[u32], repeat: Repeat) -> Vec<u8> {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let frames = delays_ms.iter().enumerate().map(|(i, delay)| {
+            Frame::from_parts(
+                RgbaImage::from_pixel(16, 16, Rgba(colors[i % colors.len()])),
+                0,
+                0,
+                Delay::from_numer_denom_ms(*delay, 1),
+            )
+        });
+
+        let mut content = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut content);
+            encoder.set_repeat(repeat).unwrap();
+            encoder.encode_frames(frames).unwrap();
+        }
+        content
+    }
+
+    /// 16x16 APNG with one solid frame per delay
+    fn apng(delays_ms: &[u16], plays: u32) -> Vec<u8> {
+        let mut content = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut content, 16, 16);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(delays_ms.len() as u32, plays).unwrap();
+
+            let mut writer = encoder.write_header().unwrap();
+            for (i, delay) in delays_ms.iter().enumerate() {
+                writer.set_frame_delay(*delay, 1000).unwrap();
+                let color = [(i * 80) as u8, 0, 0, 255];
+                writer
+                    .write_image_data(&RgbaImage::from_pixel(16, 16, Rgba(color)).into_raw())
+                    .unwrap();
+            }
+        }
+        content
+    }
+
+    fn webp(content: &[u8]) -> Vec<u8> {
+        let resize_options = ResizeOptions {
+            fit: Fit::Cover,
+            filter: FilterType::Lanczos3,
+            gravity: Gravity::Center,
+            background: Rgba([255, 255, 255, 255]),
+        };
+        let options = EncodeOptions {
+            quality: 80,
+            lossless: true,
+            compression: PngCompression::Default,
+            palette: None,
+        };
+
+        crate::systems::images::webp::run_animated(content, 16, 16, &resize_options, &options)
+            .unwrap()
+    }
+
+    fn assert_info(content: &[u8], frames: usize, loop_count: Option<u16>, duration_ms: u64) {
+        let info = info(content);
+        assert_eq!(
+            (info.frames, info.loop_count, info.duration_ms),
+            (frames, loop_count, duration_ms)
+        );
+    }
+
+    #[test]
+    fn reads_the_structure_of_gif() {
+        assert_info(&gif(&[100, 200], Repeat::Finite(3)), 2, Some(3), 300);
+        // Delays of 10ms or less are displayed as 100ms
+        assert_info(&gif(&[0, 50, 10], Repeat::Infinite), 3, Some(0), 250);
+        assert_info(&gif(&[100], Repeat::Infinite), 1, Some(0), 0);
+    }
+
+    #[test]
+    fn reads_the_structure_of_apng() {
+        assert_info(&apng(&[100, 200, 5], 0), 3, Some(0), 400);
+        assert_info(&apng(&[100, 200], 1), 2, None, 300);
+        assert_info(&apng(&[100, 200], 4), 2, Some(3), 300);
+
+        let mut still = Vec::new();
+        DynamicImage::new_rgba8(16, 16)
+            .write_to(&mut Cursor::new(&mut still), ImageFormat::Png)
+            .unwrap();
+        assert_info(&still, 1, None, 0);
+    }
+
+    #[test]
+    fn reads_the_structure_of_webp() {
+        assert_info(&webp(&gif(&[100, 200], Repeat::Finite(3))), 2, Some(3), 300);
+        assert_info(&webp(&gif(&[0, 50, 10], Repeat::Infinite)), 3, Some(0), 250);
+
+        let still = crate::systems::images::webp::run(
+            &DynamicImage::new_rgba8(16, 16),
+            &EncodeOptions {
+                quality: 80,
+                lossless: false,
+                compression: PngCompression::Default,
+                palette: None,
+            },
+        )
+        .unwrap();
+        assert_info(&still, 1, None, 0);
+    }
+}
@@ -0,0 +1,31 @@
+use image::{
+    error::{EncodingError, ImageFormatHint},
+    DynamicImage, ImageError, ImageResult,
+};
+use rgb::FromSlice;
+
+use super::EncodeOptions;
+
+// Encoder speed used for every AVIF, 1 = slowest/smallest, 10 = fastest
+const SPEED: u8 = 8;
+
+pub fn run(image: &DynamicImage, options: &EncodeOptions) -> ImageResult<Vec<u8>> {
+    let rgba = image.to_rgba8();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(options.quality as f32)
+        .with_speed(SPEED)
+        .encode_rgba(ravif::Img::new(
+            rgba.as_raw().as_rgba(),
+            rgba.width() as usize,
+            rgba.height() as usize,
+        ))
+        .map_err(|err| {
+            ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Name("avif".to_string()),
+                err,
+            ))
+        })?;
+
+    Ok(encoded.avif_file)
+}
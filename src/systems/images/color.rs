@@ -0,0 +1,85 @@
+use image::{imageops::FilterType, DynamicImage};
+
+// Colors are counted on a thumbnail, in buckets of 4 bits per channel
+const THUMBNAIL_SIZE: u32 = 64;
+const BUCKET_BITS: u32 = 4;
+
+/// Most common color of the visible pixels, None for a fully transparent image
+pub fn dominant_color(image: &DynamicImage) -> Option<[u8; 3]> {
+    let thumbnail = image
+        .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .into_rgba8();
+
+    // Count and sum of the colors of each bucket
+    let mut buckets = vec![(0u32, [0u32; 3]); 1 << (BUCKET_BITS * 3)];
+    for pixel in thumbnail.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+
+        let shift = 8 - BUCKET_BITS;
+        let index = ((r as usize >> shift) << (BUCKET_BITS * 2))
+            | ((g as usize >> shift) << BUCKET_BITS)
+            | (b as usize >> shift);
+        let (count, sum) = &mut buckets[index];
+        *count += 1;
+        sum[0] += r as u32;
+        sum[1] += g as u32;
+        sum[2] += b as u32;
+    }
+
+    // Average of the most common bucket
+    let (count, sum) = buckets.into_iter().max_by_key(|(count, _)| *count)?;
+    if count == 0 {
+        return None;
+    }
+
+    Some(sum.map(|channel| (channel / count) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn dominant_color_of_a_solid_image() {
+        for color in [[255, 0, 0], [18, 52, 86], [255, 255, 255]] {
+            let [r, g, b] = color;
+            let image = RgbaImage::from_pixel(100, 80, Rgba([r, g, b, 255]));
+
+            assert_eq!(
+                dominant_color(&DynamicImage::ImageRgba8(image)),
+                Some(color)
+            );
+        }
+    }
+
+    #[test]
+    fn dominant_color_is_the_most_common() {
+        // A quarter red, the rest blue
+        let image = RgbaImage::from_fn(100, 100, |x, _| match x < 25 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 255]),
+        });
+        let [r, g, b] = dominant_color(&DynamicImage::ImageRgba8(image)).unwrap();
+
+        assert!(r < 16 && g < 16 && b >= 240, "{r} {g} {b}");
+    }
+
+    #[test]
+    fn ignores_the_transparent_pixels() {
+        // A quarter opaque red, the rest transparent blue
+        let image = RgbaImage::from_fn(100, 100, |x, _| match x < 25 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 0]),
+        });
+        let [r, g, b] = dominant_color(&DynamicImage::ImageRgba8(image)).unwrap();
+        assert!(r >= 240 && g < 16 && b < 16, "{r} {g} {b}");
+
+        let transparent = RgbaImage::from_pixel(100, 80, Rgba([255, 0, 0, 0]));
+        assert_eq!(dominant_color(&DynamicImage::ImageRgba8(transparent)), None);
+    }
+}
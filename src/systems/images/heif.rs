@@ -0,0 +1,48 @@
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageError, RgbaImage,
+};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+/// Decode the primary image of a HEIC or AVIF file, `check_size` is called before any pixel
+/// is allocated
+///
+/// libheif applies the rotation and the mirroring of the container, the EXIF orientation
+/// must not be applied again.
+pub fn decode<E: From<ImageError>>(
+    content: &[u8],
+    check_size: impl FnOnce(u32, u32) -> Result<(), E>,
+) -> Result<DynamicImage, E> {
+    let context = HeifContext::read_from_bytes(content).map_err(decoding_error)?;
+    let handle = context.primary_image_handle().map_err(decoding_error)?;
+    check_size(handle.width(), handle.height())?;
+
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(decoding_error)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or_else(|| decoding_error("no RGBA plane"))?;
+
+    // Rows are padded up to the stride
+    let row_size = plane.width as usize * 4;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_size])
+        .copied()
+        .collect();
+
+    RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| decoding_error("invalid size").into())
+}
+
+fn decoding_error(err: impl ToString) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("heif".to_string()),
+        err.to_string(),
+    ))
+}
@@ -1,12 +1,15 @@
 use std::io::Cursor;
 
-use image::{DynamicImage, ImageFormat};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageResult};
 
-pub fn run(image: &DynamicImage, width: u32, height: u32) -> Vec<u8> {
-    let image = image.resize_to_fill(width, height, image::imageops::FilterType::Lanczos3);
+use super::EncodeOptions;
+
+pub fn run(image: &DynamicImage, options: &EncodeOptions) -> ImageResult<Vec<u8>> {
+    // JPEG has no alpha channel
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
 
     let mut cursor = Cursor::new(Vec::new());
-    image.write_to(&mut cursor, ImageFormat::Jpeg).unwrap();
+    JpegEncoder::new_with_quality(&mut cursor, options.quality).encode_image(&image)?;
 
-    cursor.into_inner()
+    Ok(cursor.into_inner())
 }
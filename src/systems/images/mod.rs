@@ -1,4 +1,314 @@
+pub mod animation;
+#[cfg(feature = "avif")]
+pub mod avif;
+pub mod color;
 pub mod gif;
+#[cfg(feature = "heif")]
+pub mod heif;
 pub mod jpg;
+pub mod orientation;
+pub mod placeholder;
 pub mod png;
+pub mod resize;
+pub mod svg;
+#[cfg(feature = "webm")]
+pub mod webm;
 pub mod webp;
+
+use image::{DynamicImage, ImageFormat};
+
+/// Format of a source, detected from its content since the Content-Type of the servers
+/// can't be trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    // Decoded by the image crate: PNG (and APNG), JPEG, GIF, WebP, BMP, TIFF or ICO
+    Image(ImageFormat),
+    // HEIC and AVIF are decoded by libheif with the heif feature
+    Heic,
+    Avif,
+    Svg,
+}
+
+impl SourceFormat {
+    pub fn detect(content: &[u8]) -> Option<Self> {
+        match image::guess_format(content) {
+            Ok(
+                format @ (ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Gif
+                | ImageFormat::WebP
+                | ImageFormat::Bmp
+                | ImageFormat::Tiff
+                | ImageFormat::Ico),
+            ) => Some(Self::Image(format)),
+            Ok(ImageFormat::Avif) => Some(Self::Avif),
+            Ok(_) => None,
+            Err(_) => heif_format(content).or_else(|| is_svg(content).then_some(Self::Svg)),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Image(ImageFormat::Png) => "image/png",
+            Self::Image(ImageFormat::Jpeg) => "image/jpeg",
+            Self::Image(ImageFormat::Gif) => "image/gif",
+            Self::Image(ImageFormat::WebP) => "image/webp",
+            Self::Image(ImageFormat::Bmp) => "image/bmp",
+            Self::Image(ImageFormat::Tiff) => "image/tiff",
+            Self::Image(ImageFormat::Ico) => "image/x-icon",
+            Self::Image(_) => "application/octet-stream",
+            Self::Heic => "image/heic",
+            Self::Avif => "image/avif",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+}
+
+// ISO media file whose major or compatible brands are HEIF ones
+fn heif_format(content: &[u8]) -> Option<SourceFormat> {
+    const HEIC_BRANDS: [&[u8]; 10] = [
+        b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs", b"mif1", b"msf1",
+    ];
+    const AVIF_BRANDS: [&[u8]; 2] = [b"avif", b"avis"];
+
+    // ftyp box: size (u32 big endian), "ftyp", major brand, minor version, compatible brands
+    let size = content.get(0..4)?;
+    let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+    if content.get(4..8) != Some(b"ftyp") || size < 16 {
+        return None;
+    }
+
+    let major_brand = content.get(8..12)?;
+    let compatible_brands = content.get(16..size.min(content.len())).unwrap_or_default();
+    let brands = || std::iter::once(major_brand).chain(compatible_brands.chunks_exact(4));
+
+    // mif1 is the generic brand of both, AVIF files list avif next to it
+    if brands().any(|brand| AVIF_BRANDS.contains(&brand)) {
+        Some(SourceFormat::Avif)
+    } else if brands().any(|brand| HEIC_BRANDS.contains(&brand)) {
+        Some(SourceFormat::Heic)
+    } else {
+        None
+    }
+}
+
+// XML document with an svg root, possibly after a declaration, a doctype or comments
+fn is_svg(content: &[u8]) -> bool {
+    let start = &content[..content.len().min(4096)];
+    let start = String::from_utf8_lossy(start.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(start));
+    let start = start.trim_start();
+
+    start.starts_with('<') && start.contains("<svg")
+}
+
+/// Encoder settings requested by the client, each one is part of the cache key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    // 1-100, used by JPEG, lossy WebP and AVIF
+    pub quality: u8,
+    // WebP only
+    pub lossless: bool,
+    // PNG only
+    pub compression: PngCompression,
+    // PNG only, number of colors of the palette
+    pub palette: Option<u16>,
+}
+
+impl EncodeOptions {
+    pub fn cache_key(&self) -> String {
+        format!(
+            "q{}-{}-{}-p{}",
+            self.quality,
+            if self.lossless { "lossless" } else { "lossy" },
+            self.compression.name(),
+            self.palette.unwrap_or(0)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    pub fn from_param(compression: &str) -> Option<Self> {
+        match compression.to_lowercase().as_str() {
+            "fast" => Some(Self::Fast),
+            "default" => Some(Self::Default),
+            "best" => Some(Self::Best),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fast => "fast",
+            Self::Default => "default",
+            Self::Best => "best",
+        }
+    }
+}
+
+/// Format sent to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Avif,
+    WebP,
+    Jpeg,
+    Png,
+    Gif,
+    // AV1 video, only for animations
+    WebM,
+}
+
+impl OutputFormat {
+    /// Parse the `format=` parameter, only the formats that can be encoded from any source are accepted
+    pub fn from_param(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "avif" if cfg!(feature = "avif") => Some(Self::Avif),
+            "webp" => Some(Self::WebP),
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "gif" => Some(Self::Gif),
+            "webm" if cfg!(feature = "webm") => Some(Self::WebM),
+            _ => None,
+        }
+    }
+
+    /// Pick the best format accepted by the client, JPEG if it only accepts the legacy formats
+    ///
+    /// WebM is only picked when the client lists `video/webm`, like a `<video>` element does,
+    /// and `for_source` replaces it with WebP when the source is not animated.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let accept = accept.unwrap_or_default();
+
+        if cfg!(feature = "webm") && accepts(accept, "video/webm") {
+            Self::WebM
+        } else if cfg!(feature = "avif") && accepts(accept, "image/avif") {
+            Self::Avif
+        } else if accepts(accept, "image/webp") {
+            Self::WebP
+        } else {
+            Self::Jpeg
+        }
+    }
+
+    /// Keep animations animated and never drop the transparency of the source
+    pub fn for_source(self, animated: bool, image: &DynamicImage) -> Self {
+        match self {
+            Self::WebM if animated => Self::WebM,
+            Self::WebM => Self::WebP,
+            // There is no animated AVIF encoder
+            Self::Avif if animated => Self::WebP,
+            Self::Avif | Self::WebP => self,
+            _ if animated => Self::Gif,
+            Self::Jpeg if has_transparency(image) => Self::Png,
+            format => format,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Avif => "avif",
+            Self::WebP => "webp",
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebM => "webm",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Avif => "image/avif",
+            Self::WebP => "image/webp",
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::WebM => "video/webm",
+        }
+    }
+}
+
+// Check that a media type is listed in an Accept header with a non-zero quality
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        if !params
+            .next()
+            .unwrap_or_default()
+            .eq_ignore_ascii_case(media_type)
+        {
+            return false;
+        }
+
+        params
+            .filter_map(|param| param.strip_prefix("q="))
+            .all(|quality| quality.parse::<f32>().map_or(true, |quality| quality > 0.0))
+    })
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+
+    match image.as_rgba8() {
+        Some(rgba) => rgba.pixels().any(|pixel| pixel[3] < u8::MAX),
+        None => image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_the_format() {
+        assert_eq!(OutputFormat::from_accept(None), OutputFormat::Jpeg);
+        assert_eq!(
+            OutputFormat::from_accept(Some("image/webp,image/*,*/*;q=0.8")),
+            OutputFormat::WebP
+        );
+        assert_eq!(
+            OutputFormat::from_accept(Some("image/webp;q=0,image/*")),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept(Some("image/avif,image/webp")),
+            if cfg!(feature = "avif") {
+                OutputFormat::Avif
+            } else {
+                OutputFormat::WebP
+            }
+        );
+    }
+
+    #[test]
+    fn negotiates_webm_for_animations() {
+        let accept = Some("video/webm,video/ogg,video/*;q=0.9,*/*;q=0.5");
+        let image = DynamicImage::new_rgb8(1, 1);
+
+        if cfg!(feature = "webm") {
+            assert_eq!(OutputFormat::from_accept(accept), OutputFormat::WebM);
+            assert_eq!(
+                OutputFormat::from_accept(accept).for_source(true, &image),
+                OutputFormat::WebM
+            );
+            assert_eq!(
+                OutputFormat::from_accept(accept).for_source(false, &image),
+                OutputFormat::WebP
+            );
+        } else {
+            assert_eq!(OutputFormat::from_accept(accept), OutputFormat::Jpeg);
+        }
+
+        assert_eq!(
+            OutputFormat::from_accept(Some("video/webm;q=0,image/webp")),
+            OutputFormat::WebP
+        );
+    }
+}
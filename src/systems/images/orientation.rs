@@ -0,0 +1,147 @@
+use std::io::Cursor;
+
+use image::DynamicImage;
+
+/// Read the EXIF orientation of the source, 1 (no transformation) if it has none
+pub fn read(body: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(body))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+/// Rotate and flip the image so that it is displayed upright without its EXIF metadata
+pub fn apply(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use image::{codecs::jpeg::JpegEncoder, GenericImageView, Rgb, RgbImage};
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    /// JPEG stored as `width`x`height` with a red top left pixel, an EXIF Orientation of 6
+    /// (displayed rotated by 90° clockwise) and a GPS position
+    pub fn jpeg_with_exif(width: u32, height: u32) -> Vec<u8> {
+        let mut image = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+        image.put_pixel(0, 0, RED);
+
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 100)
+            .encode_image(&image)
+            .unwrap();
+
+        // TIFF header in little endian, IFD0 at 8
+        let mut tiff = vec![b'I', b'I', 42, 0, 8, 0, 0, 0];
+        // IFD0: Orientation (SHORT) and the pointer to the GPS IFD (LONG), which is at 38
+        write_ifd(
+            &mut tiff,
+            &[
+                (0x0112, 3, 1, [6, 0, 0, 0]),
+                (0x8825, 4, 1, 38u32.to_le_bytes()),
+            ],
+        );
+        // GPS IFD: GPSLatitudeRef (ASCII) and GPSLatitude (3 RATIONAL), which is at 68
+        write_ifd(
+            &mut tiff,
+            &[
+                (0x0001, 2, 2, [b'N', 0, 0, 0]),
+                (0x0002, 5, 3, 68u32.to_le_bytes()),
+            ],
+        );
+        for (numerator, denominator) in [(48u32, 1u32), (51, 1), (2997, 100)] {
+            tiff.extend_from_slice(&numerator.to_le_bytes());
+            tiff.extend_from_slice(&denominator.to_le_bytes());
+        }
+
+        // APP1 segment right after the SOI marker
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+        let mut content = jpeg[..2].to_vec();
+        content.extend_from_slice(&[0xFF, 0xE1]);
+        content.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        content.extend_from_slice(&app1);
+        content.extend_from_slice(&jpeg[2..]);
+
+        content
+    }
+
+    // Entry count, entries of tag, type, count and value (or offset), no next IFD
+    fn write_ifd(tiff: &mut Vec<u8>, entries: &[(u16, u16, u32, [u8; 4])]) {
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(value);
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn reads_the_orientation() {
+        let content = jpeg_with_exif(40, 20);
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&content))
+            .unwrap();
+
+        assert_eq!(read(&content), 6);
+        assert!(exif
+            .get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY)
+            .is_some());
+    }
+
+    #[test]
+    fn defaults_to_no_orientation() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbImage::new(4, 2))
+            .unwrap();
+
+        assert_eq!(read(&jpeg), 1);
+        assert_eq!(read(b"not an image"), 1);
+    }
+
+    #[test]
+    fn applies_the_orientation() {
+        let mut image = RgbImage::from_pixel(4, 2, Rgb([255, 255, 255]));
+        image.put_pixel(0, 0, RED);
+        let image = DynamicImage::ImageRgb8(image);
+
+        // Size and position of the red pixel once upright
+        for (orientation, size, red) in [
+            (1, (4, 2), (0, 0)),
+            (2, (4, 2), (3, 0)),
+            (3, (4, 2), (3, 1)),
+            (4, (4, 2), (0, 1)),
+            (5, (2, 4), (0, 0)),
+            (6, (2, 4), (1, 0)),
+            (7, (2, 4), (1, 3)),
+            (8, (2, 4), (0, 3)),
+        ] {
+            let upright = apply(image.clone(), orientation);
+
+            assert_eq!(upright.dimensions(), size, "orientation {orientation}");
+            assert_eq!(
+                upright.to_rgb8().get_pixel(red.0, red.1),
+                &RED,
+                "orientation {orientation}"
+            );
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use base64::Engine;
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+
+// The hashes only keep the lowest frequencies, computing them on a thumbnail gives the same result
+const BLURHASH_MAX_SIZE: u32 = 64;
+const THUMBHASH_MAX_SIZE: u32 = 100;
+
+/// Tiny string rendered by the clients while the image loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    // Used by the NIP-94 `blurhash` tags
+    BlurHash,
+    // Keeps the aspect ratio and the transparency
+    ThumbHash,
+}
+
+impl Placeholder {
+    pub fn from_param(placeholder: &str) -> Option<Self> {
+        match placeholder.to_lowercase().as_str() {
+            "blurhash" => Some(Self::BlurHash),
+            "thumbhash" => Some(Self::ThumbHash),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BlurHash => "blurhash",
+            Self::ThumbHash => "thumbhash",
+        }
+    }
+
+    /// BlurHash string, or ThumbHash bytes encoded in base64
+    pub fn encode(&self, image: &DynamicImage) -> String {
+        match self {
+            Self::BlurHash => blurhash(image),
+            Self::ThumbHash => base64::engine::general_purpose::STANDARD.encode(thumbhash(image)),
+        }
+    }
+}
+
+// Scale the image down to fit in a square, smaller images are kept as they are
+fn thumbnail(image: &DynamicImage, max_size: u32) -> RgbaImage {
+    if image.width() <= max_size && image.height() <= max_size {
+        return image.to_rgba8();
+    }
+
+    image
+        .resize(max_size, max_size, FilterType::Triangle)
+        .into_rgba8()
+}
+
+fn blurhash(image: &DynamicImage) -> String {
+    let thumbnail = thumbnail(image, BLURHASH_MAX_SIZE);
+
+    // 4 components along the longest side
+    let (components_x, components_y) = if thumbnail.width() >= thumbnail.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    blurhash::encode(
+        components_x,
+        components_y,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    )
+    .expect("components are between 1 and 9")
+}
+
+// Port of the reference encoder of ThumbHash (https://evanw.github.io/thumbhash/)
+fn thumbhash(image: &DynamicImage) -> Vec<u8> {
+    let thumbnail = thumbnail(image, THUMBHASH_MAX_SIZE);
+    let (w, h) = (thumbnail.width() as usize, thumbnail.height() as usize);
+    let pixels = thumbnail.pixels().map(|pixel| pixel.0);
+
+    // Average color, weighted by the alpha
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0, 0.0, 0.0, 0.0);
+    for [r, g, b, a] in pixels.clone() {
+        let alpha = a as f64 / 255.0;
+        avg_r += alpha / 255.0 * r as f64;
+        avg_g += alpha / 255.0 * g as f64;
+        avg_b += alpha / 255.0 * b as f64;
+        avg_a += alpha;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < (w * h) as f64;
+    // Fewer luminance bits when there is an alpha channel
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let max_side = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / max_side).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / max_side).round() as usize).max(1);
+
+    // Luminance, yellow - blue, red - green and alpha, composited over the average color
+    let (mut l, mut p, mut q, mut a) = (vec![], vec![], vec![], vec![]);
+    for [r, g, b, alpha] in pixels {
+        let alpha = alpha as f64 / 255.0;
+        let r = avg_r * (1.0 - alpha) + alpha / 255.0 * r as f64;
+        let g = avg_g * (1.0 - alpha) + alpha / 255.0 * g as f64;
+        let b = avg_b * (1.0 - alpha) + alpha / 255.0 * b as f64;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, w, h, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, w, h, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, w, h, 3, 3);
+    let (a_dc, a_ac, a_scale) = if has_alpha {
+        encode_channel(&a, w, h, 5, 5)
+    } else {
+        (1.0, vec![], 1.0)
+    };
+
+    // Constants
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u16
+        | ((63.0 * p_scale).round() as u16) << 3
+        | ((63.0 * q_scale).round() as u16) << 9
+        | (is_landscape as u16) << 15;
+
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+    if has_alpha {
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+    }
+
+    // Varying factors, two per byte
+    let ac_start = hash.len();
+    for (index, factor) in [l_ac, p_ac, q_ac, a_ac].concat().into_iter().enumerate() {
+        if index % 2 == 0 {
+            hash.push(0);
+        }
+        hash[ac_start + index / 2] |= ((15.0 * factor).round() as u8) << ((index % 2) * 4);
+    }
+
+    hash
+}
+
+// DCT of a channel: constant term, normalized varying terms and their scale
+fn encode_channel(
+    channel: &[f64],
+    w: usize,
+    h: usize,
+    nx: usize,
+    ny: usize,
+) -> (f64, Vec<f64>, f64) {
+    let pi = std::f64::consts::PI;
+    let (mut dc, mut ac, mut scale) = (0.0, vec![], 0.0_f64);
+
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            let fx: Vec<f64> = (0..w)
+                .map(|x| (pi / w as f64 * cx as f64 * (x as f64 + 0.5)).cos())
+                .collect();
+
+            let mut f = 0.0;
+            for y in 0..h {
+                let fy = (pi / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                for x in 0..w {
+                    f += channel[x + y * w] * fx[x] * fy;
+                }
+            }
+            f /= (w * h) as f64;
+
+            if cx > 0 || cy > 0 {
+                ac.push(f);
+                scale = scale.max(f.abs());
+            } else {
+                dc = f;
+            }
+            cx += 1;
+        }
+    }
+
+    if scale > 0.0 {
+        for factor in &mut ac {
+            *factor = 0.5 + 0.5 / scale * *factor;
+        }
+    }
+
+    (dc, ac, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    // Horizontal red and vertical green gradients, with a diagonal alpha gradient
+    fn gradient(width: u32, height: u32, alpha: bool) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([
+                (x * 255 / (width - 1)) as u8,
+                (y * 255 / (height - 1)) as u8,
+                128,
+                match alpha {
+                    true => ((x + y) * 255 / (width + height - 2)) as u8,
+                    false => 255,
+                },
+            ])
+        }))
+    }
+
+    #[test]
+    fn thumbhash_matches_the_reference_encoder() {
+        // Computed by rgbaToThumbHash of https://github.com/evanw/thumbhash/blob/main/js/thumbhash.js
+        for (image, hash) in [
+            (gradient(8, 6, false), "4PcJRZ6Ah4dwiHeHh3iHh4BwB/iH"),
+            (gradient(6, 8, false), "4AcKRR5wd3B4iHh4iHh4h3BwCPd4"),
+            (gradient(8, 6, true), "5AiGHJAnoYfAh3p3GAqsoX8GdyiIeIiHBw=="),
+        ] {
+            assert_eq!(Placeholder::ThumbHash.encode(&image), hash);
+        }
+    }
+
+    #[test]
+    fn thumbhash_keeps_the_average_color() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255])));
+        let hash = thumbhash(&image);
+
+        // thumbHashToAverageRGBA of the reference decoder
+        let header = hash[0] as u32 | (hash[1] as u32) << 8 | (hash[2] as u32) << 16;
+        let l = (header & 63) as f64 / 63.0;
+        let p = ((header >> 6) & 63) as f64 / 31.5 - 1.0;
+        let q = ((header >> 12) & 63) as f64 / 31.5 - 1.0;
+        let b = l - 2.0 / 3.0 * p;
+        let r = (3.0 * l - b + q) / 2.0;
+        let g = r - q;
+
+        assert_eq!(header >> 23, 0);
+        for (channel, expected) in [(r, 1.0), (g, 0.0), (b, 0.0)] {
+            assert!((channel - expected).abs() < 0.05, "{channel} != {expected}");
+        }
+    }
+
+    const BASE83: &str =
+        "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+    fn base83(chars: &str) -> usize {
+        chars
+            .chars()
+            .fold(0, |value, c| value * 83 + BASE83.find(c).unwrap())
+    }
+
+    #[test]
+    fn blurhash_has_4_components_on_the_longest_side() {
+        // The size flag is (x - 1) + (y - 1) * 9, then 5 characters for the maximum and
+        // the average color, and 2 for each other component
+        let landscape = Placeholder::BlurHash.encode(&gradient(80, 60, false));
+        assert_eq!(base83(&landscape[0..1]), 3 + 2 * 9);
+        assert_eq!(landscape.len(), 6 + 2 * (4 * 3 - 1));
+
+        let portrait = Placeholder::BlurHash.encode(&gradient(60, 80, false));
+        assert_eq!(base83(&portrait[0..1]), 2 + 3 * 9);
+        assert_eq!(portrait.len(), 6 + 2 * (3 * 4 - 1));
+    }
+
+    #[test]
+    fn blurhash_keeps_the_average_color() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255])));
+        let hash = Placeholder::BlurHash.encode(&image);
+
+        // The sRGB conversions of the blurhash crate are lookup tables, off by 1 at most
+        let average = base83(&hash[2..6]);
+        let channels = [average >> 16, (average >> 8) & 255, average & 255];
+        for (channel, expected) in channels.into_iter().zip([255, 0, 0]) {
+            assert!(channel.abs_diff(expected) <= 1, "{channel} != {expected}");
+        }
+    }
+}
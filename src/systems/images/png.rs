@@ -1,12 +1,75 @@
 use std::io::Cursor;
 
-use image::{DynamicImage, ImageFormat};
+use image::{
+    codecs::png::{CompressionType, FilterType, PngEncoder},
+    error::{EncodingError, ImageFormatHint},
+    DynamicImage, ImageEncoder, ImageError, ImageFormat, ImageResult, RgbaImage,
+};
 
-pub fn run(image: &DynamicImage, width: u32, height: u32) -> Vec<u8> {
-    let image = image.resize_to_fill(width, height, image::imageops::FilterType::Lanczos3);
+use super::{EncodeOptions, PngCompression};
+
+pub fn run(image: &DynamicImage, options: &EncodeOptions) -> ImageResult<Vec<u8>> {
+    if let Some(colors) = options.palette {
+        return run_palette(&image.to_rgba8(), colors, options.compression);
+    }
+
+    let compression = match options.compression {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
 
     let mut cursor = Cursor::new(Vec::new());
-    image.write_to(&mut cursor, ImageFormat::Png).unwrap();
+    PngEncoder::new_with_quality(&mut cursor, compression, FilterType::Adaptive).write_image(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color(),
+    )?;
+
+    Ok(cursor.into_inner())
+}
+
+// Quantize the image to an indexed PNG, the image crate only writes truecolor PNGs
+fn run_palette(
+    image: &RgbaImage,
+    colors: u16,
+    compression: PngCompression,
+) -> ImageResult<Vec<u8>> {
+    let quantizer = color_quant::NeuQuant::new(10, colors as usize, image.as_raw());
+    let color_map = quantizer.color_map_rgba();
+
+    let palette: Vec<u8> = color_map
+        .chunks(4)
+        .flat_map(|color| color[..3].to_vec())
+        .collect();
+    let transparency: Vec<u8> = color_map.chunks(4).map(|color| color[3]).collect();
+    let indexes: Vec<u8> = image
+        .pixels()
+        .map(|pixel| quantizer.index_of(&pixel.0) as u8)
+        .collect();
+
+    let mut content = Vec::new();
+    let mut encoder = png::Encoder::new(&mut content, image.width(), image.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette);
+    encoder.set_trns(transparency);
+    encoder.set_compression(match compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&indexes))
+        .map_err(|err| {
+            ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Png),
+                err,
+            ))
+        })?;
 
-    cursor.into_inner()
+    Ok(content)
 }
@@ -0,0 +1,287 @@
+use image::{imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+
+/// How the image is fitted into the requested width and height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    // Fit inside the box, keeping the ratio
+    Contain,
+    // Fill the box, keeping the ratio and cropping the overflow
+    Cover,
+    // Fill the box, stretching the image
+    Fill,
+    // Like contain, but never enlarge the image
+    Inside,
+    // Like contain, then center the image on a box filled with the background color
+    Pad,
+}
+
+impl Fit {
+    pub fn from_param(fit: &str) -> Option<Self> {
+        match fit.to_lowercase().as_str() {
+            "contain" => Some(Self::Contain),
+            "cover" => Some(Self::Cover),
+            "fill" => Some(Self::Fill),
+            "inside" => Some(Self::Inside),
+            "pad" => Some(Self::Pad),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Contain => "contain",
+            Self::Cover => "cover",
+            Self::Fill => "fill",
+            Self::Inside => "inside",
+            Self::Pad => "pad",
+        }
+    }
+}
+
+/// Part of the image kept when `Fit::Cover` crops it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    // Point to keep as close as possible to the center, from 0.0 to 1.0 on both axes
+    Focus(f32, f32),
+    // Keep the most detailed part of the image
+    Attention,
+}
+
+impl Gravity {
+    pub fn from_param(gravity: &str) -> Option<Self> {
+        match gravity.to_lowercase().as_str() {
+            "center" | "centre" => Some(Self::Center),
+            "north" => Some(Self::North),
+            "south" => Some(Self::South),
+            "east" => Some(Self::East),
+            "west" => Some(Self::West),
+            "attention" => Some(Self::Attention),
+            _ => None,
+        }
+    }
+
+    /// Parse a "x,y" focal point, both coordinates between 0.0 and 1.0
+    pub fn from_focus_param(focus: &str) -> Option<Self> {
+        let (x, y) = focus.split_once(',')?;
+        let x: f32 = x.trim().parse().ok()?;
+        let y: f32 = y.trim().parse().ok()?;
+
+        if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+            return None;
+        }
+
+        Some(Self::Focus(x, y))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Center => "center".to_string(),
+            Self::North => "north".to_string(),
+            Self::South => "south".to_string(),
+            Self::East => "east".to_string(),
+            Self::West => "west".to_string(),
+            Self::Focus(x, y) => format!("focus{x},{y}"),
+            Self::Attention => "attention".to_string(),
+        }
+    }
+
+    // Focal point of the gravity, the attention is computed from the image
+    fn focus(&self, image: &DynamicImage, width: u32, height: u32) -> (f32, f32) {
+        match self {
+            Self::Center => (0.5, 0.5),
+            Self::North => (0.5, 0.0),
+            Self::South => (0.5, 1.0),
+            Self::East => (1.0, 0.5),
+            Self::West => (0.0, 0.5),
+            Self::Focus(x, y) => (*x, *y),
+            Self::Attention => attention_focus(image, width, height),
+        }
+    }
+}
+
+/// Resize settings requested by the client, each one is part of the cache key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeOptions {
+    pub fit: Fit,
+    pub filter: FilterType,
+    // Cover only
+    pub gravity: Gravity,
+    // Pad only
+    pub background: Rgba<u8>,
+}
+
+impl ResizeOptions {
+    pub fn cache_key(&self) -> String {
+        let [r, g, b, a] = self.background.0;
+
+        format!(
+            "{}-{}-{}-{r:02x}{g:02x}{b:02x}{a:02x}",
+            self.fit.name(),
+            filter_name(self.filter),
+            self.gravity.name()
+        )
+    }
+
+    /// Replace the attention gravity by the focal point it finds in the image,
+    /// so that every frame of an animation is cropped the same way
+    pub fn resolve_attention(&self, image: &DynamicImage, width: u32, height: u32) -> Self {
+        match self.gravity {
+            Gravity::Attention if self.fit == Fit::Cover => {
+                let (x, y) = attention_focus(image, width, height);
+                Self {
+                    gravity: Gravity::Focus(x, y),
+                    ..*self
+                }
+            }
+            _ => *self,
+        }
+    }
+}
+
+pub fn filter_from_param(filter: &str) -> Option<FilterType> {
+    match filter.to_lowercase().as_str() {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" | "bilinear" => Some(FilterType::Triangle),
+        "catmullrom" | "bicubic" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+fn filter_name(filter: FilterType) -> &'static str {
+    match filter {
+        FilterType::Nearest => "nearest",
+        FilterType::Triangle => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian => "gaussian",
+        FilterType::Lanczos3 => "lanczos3",
+    }
+}
+
+/// Parse a "rrggbb" or "rrggbbaa" hex color, with or without a leading "#"
+pub fn color_from_param(color: &str) -> Option<Rgba<u8>> {
+    let color = color.trim_start_matches('#');
+    if !(color.len() == 6 || color.len() == 8) {
+        return None;
+    }
+
+    let bytes = hex::decode(color).ok()?;
+    Some(Rgba([
+        bytes[0],
+        bytes[1],
+        bytes[2],
+        bytes.get(3).copied().unwrap_or(u8::MAX),
+    ]))
+}
+
+pub fn resize(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    options: &ResizeOptions,
+) -> DynamicImage {
+    match options.fit {
+        Fit::Contain => image.resize(width, height, options.filter),
+        Fit::Cover => cover(image, width, height, options),
+        Fit::Fill => image.resize_exact(width, height, options.filter),
+        Fit::Inside if image.width() <= width && image.height() <= height => image.clone(),
+        Fit::Inside => image.resize(width, height, options.filter),
+        Fit::Pad => {
+            let resized = image.resize(width, height, options.filter);
+            let mut canvas = RgbaImage::from_pixel(width, height, options.background);
+
+            let x = (width - resized.width()) / 2;
+            let y = (height - resized.height()) / 2;
+            image::imageops::overlay(&mut canvas, &resized.to_rgba8(), x as i64, y as i64);
+
+            DynamicImage::ImageRgba8(canvas)
+        }
+    }
+}
+
+// Scale the image to cover the box, then crop the overflow around the focal point of the gravity
+fn cover(image: &DynamicImage, width: u32, height: u32, options: &ResizeOptions) -> DynamicImage {
+    let scale = (width as f64 / image.width() as f64).max(height as f64 / image.height() as f64);
+    let scaled_width = ((image.width() as f64 * scale).round() as u32).max(width);
+    let scaled_height = ((image.height() as f64 * scale).round() as u32).max(height);
+
+    let (focus_x, focus_y) = options.gravity.focus(image, width, height);
+    let scaled = image.resize_exact(scaled_width, scaled_height, options.filter);
+
+    let x = crop_offset(focus_x, scaled_width, width);
+    let y = crop_offset(focus_y, scaled_height, height);
+
+    scaled.crop_imm(x, y, width, height)
+}
+
+// Offset of the crop that puts the focal point as close as possible to its center
+fn crop_offset(focus: f32, size: u32, crop_size: u32) -> u32 {
+    let offset = focus as f64 * size as f64 - crop_size as f64 / 2.0;
+
+    offset.clamp(0.0, (size - crop_size) as f64).round() as u32
+}
+
+// Side of the thumbnail analyzed by the attention gravity
+const ATTENTION_SIZE: u32 = 128;
+
+/// Find the crop with the most edges, returned as the focal point of its center
+///
+/// The edge energy (gradient magnitude of the luminance) is computed on a thumbnail,
+/// then a window of the target ratio slides along the axis that overflows.
+fn attention_focus(image: &DynamicImage, width: u32, height: u32) -> (f32, f32) {
+    let thumbnail = image
+        .resize(ATTENTION_SIZE, ATTENTION_SIZE, FilterType::Triangle)
+        .to_luma8();
+    let (thumb_width, thumb_height) = thumbnail.dimensions();
+    if thumb_width < 3 || thumb_height < 3 {
+        return (0.5, 0.5);
+    }
+
+    // Energy of each column and row
+    let mut columns = vec![0u64; thumb_width as usize];
+    let mut rows = vec![0u64; thumb_height as usize];
+    for y in 1..thumb_height - 1 {
+        for x in 1..thumb_width - 1 {
+            let pixel = |x: u32, y: u32| thumbnail.get_pixel(x, y)[0] as i32;
+            let energy = ((pixel(x + 1, y) - pixel(x - 1, y)).abs()
+                + (pixel(x, y + 1) - pixel(x, y - 1)).abs()) as u64;
+
+            columns[x as usize] += energy;
+            rows[y as usize] += energy;
+        }
+    }
+
+    let target_ratio = width as f64 / height as f64;
+    let source_ratio = thumb_width as f64 / thumb_height as f64;
+
+    if source_ratio > target_ratio {
+        let window = (thumb_height as f64 * target_ratio).round() as usize;
+        (best_window_center(&columns, window), 0.5)
+    } else {
+        let window = (thumb_width as f64 / target_ratio).round() as usize;
+        (0.5, best_window_center(&rows, window))
+    }
+}
+
+// Center of the window with the highest sum, as a fraction of the profile length
+fn best_window_center(profile: &[u64], window: usize) -> f32 {
+    let window = window.clamp(1, profile.len());
+
+    let mut sum: u64 = profile[..window].iter().sum();
+    let (mut best_sum, mut best_start) = (sum, 0);
+    for start in 1..=profile.len() - window {
+        sum = sum + profile[start + window - 1] - profile[start - 1];
+        if sum > best_sum {
+            best_sum = sum;
+            best_start = start;
+        }
+    }
+
+    (best_start as f32 + window as f32 / 2.0) / profile.len() as f32
+}
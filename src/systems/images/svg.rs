@@ -0,0 +1,51 @@
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageError, RgbaImage,
+};
+use resvg::{tiny_skia, usvg};
+
+/// Rasterize an SVG at its own size, `check_size` is called before any pixel is allocated
+///
+/// Nothing outside of the document is loaded: no file, no URL and no embedded image.
+/// resvg is built without text support, so no system font is read either.
+pub fn decode<E: From<ImageError>>(
+    content: &[u8],
+    check_size: impl FnOnce(u32, u32) -> Result<(), E>,
+) -> Result<DynamicImage, E> {
+    let options = usvg::Options {
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: Box::new(|_, _, _| None),
+            resolve_string: Box::new(|_, _| None),
+        },
+        ..Default::default()
+    };
+
+    let tree = usvg::Tree::from_data(content, &options).map_err(decoding_error)?;
+    let size = tree.size().to_int_size();
+    check_size(size.width(), size.height())?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| decoding_error("invalid size"))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+    // The pixmap is premultiplied by the alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(size.width(), size.height(), pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| decoding_error("invalid size").into())
+}
+
+fn decoding_error(err: impl ToString) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("svg".to_string()),
+        err.to_string(),
+    ))
+}
@@ -0,0 +1,397 @@
+use image::{
+    error::{EncodingError, ImageFormatHint},
+    ImageError, ImageResult, RgbaImage,
+};
+use rav1e::prelude::*;
+
+use super::{animation, resize::ResizeOptions, EncodeOptions};
+
+// Encoder speed used for every video, 0 = slowest/smallest, 10 = fastest
+const SPEED: u8 = 10;
+
+// Timestamps are written in milliseconds
+const TIMESTAMP_SCALE: u64 = 1_000_000;
+
+/// Transcode an animation into an AV1 WebM video, each frame keeping its own duration
+///
+/// Videos have no transparency, transparent pixels are drawn over the background color.
+pub fn run(
+    content: &[u8],
+    new_width: u32,
+    new_height: u32,
+    resize_options: &ResizeOptions,
+    options: &EncodeOptions,
+) -> ImageResult<Vec<u8>> {
+    let mut config = EncoderConfig::with_speed_preset(SPEED);
+    config.width = new_width as usize;
+    config.height = new_height as usize;
+    config.quantizer = (255.0 * (1.0 - options.quality as f64 / 100.0)).round() as usize;
+    // Packets come out in the order of the frames
+    config.low_latency = true;
+
+    let mut context: Context<u8> = Config::new()
+        .with_encoder_config(config)
+        .new_context()
+        .map_err(|err| encoding_error(&err.to_string()))?;
+
+    let background = resize_options.background;
+    let mut timestamps = Vec::new();
+    let mut duration = 0;
+    let mut blocks = Vec::new();
+
+    animation::for_each_frame(
+        content,
+        new_width,
+        new_height,
+        resize_options,
+        |image, delay| {
+            let mut frame = context.new_frame();
+            let (y, u, v) = to_yuv420(&image, background.0);
+            frame.planes[0].copy_from_raw_u8(&y, new_width as usize, 1);
+            frame.planes[1].copy_from_raw_u8(&u, new_width.div_ceil(2) as usize, 1);
+            frame.planes[2].copy_from_raw_u8(&v, new_width.div_ceil(2) as usize, 1);
+
+            timestamps.push(duration);
+            duration += animation::displayed_delay_ms(delay) as u64;
+
+            context
+                .send_frame(frame)
+                .map_err(|err| encoding_error(&err.to_string()))?;
+            receive_packets(&mut context, &timestamps, &mut blocks)
+        },
+    )?;
+
+    context.flush();
+    receive_packets(&mut context, &timestamps, &mut blocks)?;
+
+    // The codec configuration must include the sequence header, which is sent with the key frames
+    let mut codec_private = context.container_sequence_header();
+    if let Some((_, _, data)) = blocks.first() {
+        codec_private.extend(sequence_header_obu(data).unwrap_or_default());
+    }
+
+    Ok(mux(
+        new_width,
+        new_height,
+        &codec_private,
+        &blocks,
+        duration,
+    ))
+}
+
+// Find the sequence header OBU of a temporal unit, the OBUs are written with their size
+fn sequence_header_obu(data: &[u8]) -> Option<&[u8]> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data[pos];
+        let obu_type = (header >> 3) & 0x0F;
+        let has_extension = header & 0x04 != 0;
+
+        // LEB128 size after the header (and its extension)
+        let mut size_pos = pos + 1 + has_extension as usize;
+        let mut size = 0;
+        for shift in 0..8 {
+            let byte = *data.get(size_pos)?;
+            size_pos += 1;
+            size |= ((byte & 0x7F) as usize) << (shift * 7);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let end = size_pos + size;
+        if obu_type == 1 {
+            return data.get(pos..end);
+        }
+        pos = end;
+    }
+
+    None
+}
+
+// Encoded frame: timestamp in milliseconds, is key frame, data
+type Block = (u64, bool, Vec<u8>);
+
+fn receive_packets(
+    context: &mut Context<u8>,
+    timestamps: &[u64],
+    blocks: &mut Vec<Block>,
+) -> ImageResult<()> {
+    loop {
+        match context.receive_packet() {
+            Ok(packet) => blocks.push((
+                timestamps[packet.input_frameno as usize],
+                packet.frame_type == FrameType::KEY,
+                packet.data,
+            )),
+            Err(EncoderStatus::Encoded) => {}
+            Err(EncoderStatus::NeedMoreData) | Err(EncoderStatus::LimitReached) => return Ok(()),
+            Err(err) => return Err(encoding_error(&err.to_string())),
+        }
+    }
+}
+
+// BT.601 limited range, the chroma planes are averaged over blocks of 2x2 pixels
+fn to_yuv420(image: &RgbaImage, background: [u8; 4]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let (width, height) = image.dimensions();
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+
+    let rgb = |x: u32, y: u32| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let blend = |color: u8, background: u8| {
+            (color as f32 * a as f32 + background as f32 * (255 - a) as f32) / 255.0
+        };
+        (
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        )
+    };
+
+    let mut y_plane = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = rgb(x, y);
+            y_plane.push((16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0).round() as u8);
+        }
+    }
+
+    let mut u_plane = Vec::with_capacity((chroma_width * chroma_height) as usize);
+    let mut v_plane = Vec::with_capacity((chroma_width * chroma_height) as usize);
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            let (mut r, mut g, mut b, mut count) = (0.0, 0.0, 0.0, 0.0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                if x * 2 + dx < width && y * 2 + dy < height {
+                    let pixel = rgb(x * 2 + dx, y * 2 + dy);
+                    r += pixel.0;
+                    g += pixel.1;
+                    b += pixel.2;
+                    count += 1.0;
+                }
+            }
+            let (r, g, b) = (r / count, g / count, b / count);
+
+            u_plane.push((128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0).round() as u8);
+            v_plane.push((128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0).round() as u8);
+        }
+    }
+
+    (y_plane, u_plane, v_plane)
+}
+
+// Minimal Matroska (WebM) file with one AV1 video track
+fn mux(width: u32, height: u32, codec_private: &[u8], blocks: &[Block], duration: u64) -> Vec<u8> {
+    let header = element(
+        &[0x1A, 0x45, 0xDF, 0xA3],
+        &[
+            uint_element(&[0x42, 0x86], 1),  // EBMLVersion
+            uint_element(&[0x42, 0xF7], 1),  // EBMLReadVersion
+            uint_element(&[0x42, 0xF2], 4),  // EBMLMaxIDLength
+            uint_element(&[0x42, 0xF3], 8),  // EBMLMaxSizeLength
+            element(&[0x42, 0x82], b"webm"), // DocType
+            uint_element(&[0x42, 0x87], 4),  // DocTypeVersion
+            uint_element(&[0x42, 0x85], 2),  // DocTypeReadVersion
+        ]
+        .concat(),
+    );
+
+    let info = element(
+        &[0x15, 0x49, 0xA9, 0x66],
+        &[
+            uint_element(&[0x2A, 0xD7, 0xB1], TIMESTAMP_SCALE), // TimestampScale
+            element(&[0x4D, 0x80], b"safer-nostr"),             // MuxingApp
+            element(&[0x57, 0x41], b"safer-nostr"),             // WritingApp
+            element(&[0x44, 0x89], &(duration as f64).to_be_bytes()), // Duration
+        ]
+        .concat(),
+    );
+
+    let video = element(
+        &[0xE0],
+        &[
+            uint_element(&[0xB0], width as u64),  // PixelWidth
+            uint_element(&[0xBA], height as u64), // PixelHeight
+        ]
+        .concat(),
+    );
+    let tracks = element(
+        &[0x16, 0x54, 0xAE, 0x6B],
+        &element(
+            &[0xAE],
+            &[
+                uint_element(&[0xD7], 1),              // TrackNumber
+                uint_element(&[0x73, 0xC5], 1),        // TrackUID
+                uint_element(&[0x83], 1),              // TrackType: video
+                uint_element(&[0x9C], 0),              // FlagLacing
+                element(&[0x86], b"V_AV1"),            // CodecID
+                element(&[0x63, 0xA2], codec_private), // CodecPrivate
+                video,
+            ]
+            .concat(),
+        ),
+    );
+
+    // A cluster starts at each key frame, block timestamps are relative on 16 bits
+    let mut clusters = Vec::new();
+    let mut cluster: Vec<u8> = Vec::new();
+    let mut cluster_timestamp = 0;
+    for (timestamp, key_frame, data) in blocks {
+        if cluster.is_empty() || *key_frame || timestamp - cluster_timestamp > i16::MAX as u64 {
+            if !cluster.is_empty() {
+                clusters.extend(element(&[0x1F, 0x43, 0xB6, 0x75], &cluster));
+            }
+            cluster_timestamp = *timestamp;
+            cluster = uint_element(&[0xE7], cluster_timestamp); // Timestamp
+        }
+
+        // SimpleBlock: track number, relative timestamp, flags, frame
+        let mut block = vec![0x81];
+        block.extend(((timestamp - cluster_timestamp) as i16).to_be_bytes());
+        block.push(if *key_frame { 0x80 } else { 0x00 });
+        block.extend(data);
+        cluster.extend(element(&[0xA3], &block));
+    }
+    if !cluster.is_empty() {
+        clusters.extend(element(&[0x1F, 0x43, 0xB6, 0x75], &cluster));
+    }
+
+    let segment = element(
+        &[0x18, 0x53, 0x80, 0x67],
+        &[info, tracks, clusters].concat(),
+    );
+
+    [header, segment].concat()
+}
+
+// EBML element: id, size on 8 bytes, data
+fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut size = (data.len() as u64).to_be_bytes();
+    size[0] = 0x01;
+
+    [id, &size, data].concat()
+}
+
+fn uint_element(id: &[u8], value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+
+    element(id, &bytes[first..])
+}
+
+fn encoding_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Name("webm".to_string()),
+        message.to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::gif::Repeat, imageops::FilterType, Rgba};
+
+    use super::*;
+    use crate::systems::images::{
+        resize::{Fit, Gravity},
+        PngCompression,
+    };
+
+    // Element id (with its marker bits) and data of each EBML element of a buffer
+    fn elements(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = Vec::new();
+        while !data.is_empty() {
+            let id_len = data[0].leading_zeros() as usize + 1;
+            let id = data[..id_len]
+                .iter()
+                .fold(0, |id, byte| id << 8 | *byte as u32);
+
+            let size_len = data[id_len].leading_zeros() as usize + 1;
+            let size = data[id_len..id_len + size_len].iter().enumerate().fold(
+                0,
+                |size, (i, byte)| match i {
+                    0 => (*byte as usize) & (0xFF >> size_len),
+                    _ => size << 8 | *byte as usize,
+                },
+            );
+
+            let start = id_len + size_len;
+            elements.push((id, &data[start..start + size]));
+            data = &data[start + size..];
+        }
+        elements
+    }
+
+    fn child(data: &[u8], id: u32) -> &[u8] {
+        elements(data)
+            .into_iter()
+            .find(|(element_id, _)| *element_id == id)
+            .map(|(_, data)| data)
+            .unwrap_or_else(|| panic!("missing element {id:x}"))
+    }
+
+    fn uint(data: &[u8]) -> u64 {
+        data.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+    }
+
+    #[test]
+    fn muxes_the_frames_of_an_animation() {
+        let content = animation::tests::gif(&[100, 200], Repeat::Infinite);
+        let resize_options = ResizeOptions {
+            fit: Fit::Cover,
+            filter: FilterType::Triangle,
+            gravity: Gravity::Center,
+            background: Rgba([0, 0, 0, 255]),
+        };
+        let options = EncodeOptions {
+            quality: 80,
+            lossless: false,
+            compression: PngCompression::Default,
+            palette: None,
+        };
+
+        let webm = run(&content, 16, 16, &resize_options, &options).unwrap();
+
+        // EBML header then one Segment
+        let top_level = elements(&webm);
+        assert_eq!(
+            top_level.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [0x1A45DFA3, 0x18538067]
+        );
+        assert_eq!(child(top_level[0].1, 0x4282), b"webm");
+
+        // Info, Tracks then the Clusters
+        let segment = elements(top_level[1].1);
+        assert_eq!(segment[0].0, 0x1549A966);
+        assert_eq!(segment[1].0, 0x1654AE6B);
+        assert!(segment.len() > 2);
+        assert!(segment[2..].iter().all(|(id, _)| *id == 0x1F43B675));
+
+        let info = segment[0].1;
+        assert_eq!(uint(child(info, 0x2AD7B1)), TIMESTAMP_SCALE);
+        let duration = f64::from_be_bytes(child(info, 0x4489).try_into().unwrap());
+        assert_eq!(duration, 300.0);
+
+        let track = child(segment[1].1, 0xAE);
+        assert_eq!(child(track, 0x86), b"V_AV1");
+        // The sequence header OBU follows the 4 bytes of the configuration record
+        assert!(child(track, 0x63A2).len() > 4);
+        let video = child(track, 0xE0);
+        assert_eq!(uint(child(video, 0xB0)), 16);
+        assert_eq!(uint(child(video, 0xBA)), 16);
+
+        // One SimpleBlock per frame, at the timestamp of the frame, the first is a key frame
+        let mut blocks = Vec::new();
+        for (_, cluster) in &segment[2..] {
+            let cluster_timestamp = uint(child(cluster, 0xE7));
+            for (id, block) in elements(cluster) {
+                if id == 0xA3 {
+                    let relative = i16::from_be_bytes([block[1], block[2]]) as u64;
+                    blocks.push((cluster_timestamp + relative, block[3] & 0x80 != 0));
+                }
+            }
+        }
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], (0, true));
+        assert_eq!(blocks[1].0, 100);
+    }
+}
@@ -1,12 +1,152 @@
-use std::io::Cursor;
+use image::{
+    error::{EncodingError, ImageFormatHint},
+    DynamicImage, ImageError, ImageFormat, ImageResult, RgbaImage,
+};
 
-use image::{DynamicImage, ImageFormat};
+use super::{animation, resize::ResizeOptions, EncodeOptions};
 
-pub fn run(image: &DynamicImage, width: u32, height: u32) -> Vec<u8> {
-    let image = image.resize_to_fill(width, height, image::imageops::FilterType::Lanczos3);
+pub fn run(image: &DynamicImage, options: &EncodeOptions) -> ImageResult<Vec<u8>> {
+    let rgba = image.to_rgba8();
 
-    let mut cursor = Cursor::new(Vec::new());
-    image.write_to(&mut cursor, ImageFormat::WebP).unwrap();
+    // The image crate has no WebP encoder, use libwebp
+    let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+    // encode() and encode_lossless() panic on the errors of libwebp, e.g. a side over 16383
+    let content = if options.lossless {
+        // The quality of a lossless encoding is its compression effort
+        encoder.encode_simple(true, 75.0)
+    } else {
+        encoder.encode_simple(false, options.quality as f32)
+    }
+    .map_err(|err| encoding_error(&format!("{err:?}")))?;
 
-    cursor.into_inner()
+    Ok(content.to_vec())
+}
+
+/// Transcode an animation into an animated WebP, keeping the timing and the loop count
+pub fn run_animated(
+    content: &[u8],
+    new_width: u32,
+    new_height: u32,
+    resize_options: &ResizeOptions,
+    options: &EncodeOptions,
+) -> ImageResult<Vec<u8>> {
+    // The encoder borrows every frame until the end
+    let mut frames: Vec<(RgbaImage, u32)> = Vec::new();
+    animation::for_each_frame(
+        content,
+        new_width,
+        new_height,
+        resize_options,
+        |image, delay| {
+            frames.push((image, animation::displayed_delay_ms(delay)));
+            Ok(())
+        },
+    )?;
+
+    let mut config = webp::WebPConfig::new().map_err(|_| encoding_error("invalid config"))?;
+    config.quality = options.quality as f32;
+    config.lossless = options.lossless as i32;
+
+    let mut encoder = webp::AnimEncoder::new(new_width, new_height, &config);
+    // WebP counts the plays where the animation info counts the repetitions
+    encoder.set_loop_count(match animation::info(content).loop_count {
+        None => 1,
+        Some(0) => 0,
+        Some(count) => count as i32 + 1,
+    });
+
+    let mut timestamp = 0;
+    for (image, delay) in &frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            image,
+            new_width,
+            new_height,
+            timestamp as i32,
+        ));
+        timestamp += delay;
+    }
+
+    let content = encoder
+        .try_encode()
+        .map_err(|err| encoding_error(&format!("{err:?}")))?;
+
+    let mut content = content.to_vec();
+    set_total_duration(&mut content, timestamp);
+
+    Ok(content)
+}
+
+// libwebp guesses the duration of the last frame, fix it so that the animation lasts as long as the source
+fn set_total_duration(content: &mut [u8], total_duration: u32) {
+    // RIFF header then chunks of fourcc, size (u32 little endian) and padded payload
+    let mut pos = 12;
+    let mut frames = Vec::new();
+
+    while pos + 8 <= content.len() {
+        let size = u32::from_le_bytes([
+            content[pos + 4],
+            content[pos + 5],
+            content[pos + 6],
+            content[pos + 7],
+        ]) as usize;
+
+        // ANMF payload: x, y, width - 1, height - 1 and duration on 3 bytes each
+        if &content[pos..pos + 4] == b"ANMF" && pos + 23 <= content.len() {
+            frames.push(pos + 20);
+        }
+
+        pos += 8 + size + size % 2;
+    }
+
+    if let Some((last, others)) = frames.split_last() {
+        let others_duration: u32 = others
+            .iter()
+            .map(|&duration| {
+                u32::from_le_bytes([
+                    content[duration],
+                    content[duration + 1],
+                    content[duration + 2],
+                    0,
+                ])
+            })
+            .sum();
+
+        if total_duration > others_duration {
+            let last_duration = (total_duration - others_duration).min(0xFF_FFFF);
+            content[*last..*last + 3].copy_from_slice(&last_duration.to_le_bytes()[..3]);
+        }
+    }
+}
+
+fn encoding_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::WebP),
+        message.to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::images::PngCompression;
+
+    #[test]
+    fn libwebp_errors_are_returned() {
+        // WebP sides are limited to 16383 pixels
+        let image = DynamicImage::new_rgba8(16384, 1);
+
+        for lossless in [false, true] {
+            let options = EncodeOptions {
+                quality: 80,
+                lossless,
+                compression: PngCompression::Default,
+                palette: None,
+            };
+
+            assert!(matches!(
+                run(&image, &options),
+                Err(ImageError::Encoding(_))
+            ));
+        }
+    }
 }
@@ -1,7 +1,12 @@
 pub mod cache;
+pub mod disk_cache;
+pub mod fetcher;
 pub mod image_cache;
 pub mod images;
 pub mod og_extractor;
 pub mod ram_cache;
+pub mod redis_cache;
+pub mod s3;
 pub mod security;
+pub mod tiered_cache;
 pub mod url;
@@ -1,62 +1,318 @@
-use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::Serialize;
+use std::sync::Arc;
 
-// Each HashMap is a key-value store with time-to-live (TTL) in seconds in value
-#[derive(Clone, Debug)]
+use super::cache::{CacheBackend, CacheError};
+
+#[derive(Debug, Serialize)]
+pub struct RamCacheStats {
+    pub images: usize,
+    pub texts: usize,
+    pub bytes: usize,
+    pub limit_objects: usize,
+    pub limit_bytes: usize,
+    pub evictions: u64,
+}
+
+// Value, expiration timestamp and last access tick
+type RamEntry<T> = (T, usize, u64);
+
+// Each LruCache is a key-value store with time-to-live (TTL) in seconds in value
+// Both share the same object and byte budget, the least recently used entry of the two is evicted first
+#[derive(Debug)]
 pub struct RamCache {
-    pub images: HashMap<String, (Vec<u8>, usize)>,
-    pub texts: HashMap<String, (String, usize)>,
+    pub images: LruCache<String, RamEntry<Vec<u8>>>,
+    pub texts: LruCache<String, RamEntry<String>>,
+    limit_objects: usize,
+    limit_bytes: usize,
+    bytes: usize,
+    tick: u64,
+    evictions: u64,
 }
 
 impl RamCache {
-    pub fn new() -> Self {
+    pub fn new(limit_objects: usize, limit_bytes: usize) -> Self {
         Self {
-            images: HashMap::new(),
-            texts: HashMap::new(),
+            images: LruCache::unbounded(),
+            texts: LruCache::unbounded(),
+            limit_objects,
+            limit_bytes,
+            bytes: 0,
+            tick: 0,
+            evictions: 0,
         }
     }
 
     pub fn calc_ttl(ttl_in_seconds: usize) -> usize {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize;
-
-        now + ttl_in_seconds
+        now() + ttl_in_seconds
     }
 
-    pub fn get_image(&self, key: &str) -> Option<&Vec<u8>> {
-        if let Some((value, _)) = self.images.get(key) {
-            Some(value)
-        } else {
-            None
+    pub fn get_image(&mut self, key: &str) -> Option<&Vec<u8>> {
+        self.tick += 1;
+        match self.images.get_mut(key) {
+            Some((value, ttl, last_access)) if *ttl > now() => {
+                *last_access = self.tick;
+                Some(value)
+            }
+            _ => None,
         }
     }
 
-    pub fn get_str(&self, key: &str) -> Option<&String> {
-        if let Some((value, _)) = self.texts.get(key) {
-            Some(value)
-        } else {
-            None
+    pub fn get_str(&mut self, key: &str) -> Option<&String> {
+        self.tick += 1;
+        match self.texts.get_mut(key) {
+            Some((value, ttl, last_access)) if *ttl > now() => {
+                *last_access = self.tick;
+                Some(value)
+            }
+            _ => None,
         }
     }
 
-    pub fn set_image(&mut self, key: &str, value: &Vec<u8>, ttl: usize) {
-        self.images
-            .insert(key.to_string(), (value.to_vec(), RamCache::calc_ttl(ttl)));
+    pub fn set_image(&mut self, key: &str, value: &[u8], ttl: usize) {
+        self.tick += 1;
+        self.bytes += key.len() + value.len();
+        if let Some((old_key, (old_value, ..))) = self.images.push(
+            key.to_string(),
+            (value.to_vec(), RamCache::calc_ttl(ttl), self.tick),
+        ) {
+            self.bytes -= old_key.len() + old_value.len();
+        }
+
+        self.evict();
     }
 
     pub fn set_str(&mut self, key: &str, value: String, ttl: usize) {
-        self.texts
-            .insert(key.to_string(), (value, RamCache::calc_ttl(ttl)));
+        self.tick += 1;
+        self.bytes += key.len() + value.len();
+        if let Some((old_key, (old_value, ..))) = self
+            .texts
+            .push(key.to_string(), (value, RamCache::calc_ttl(ttl), self.tick))
+        {
+            self.bytes -= old_key.len() + old_value.len();
+        }
+
+        self.evict();
+    }
+
+    pub fn stats(&self) -> RamCacheStats {
+        RamCacheStats {
+            images: self.images.len(),
+            texts: self.texts.len(),
+            bytes: self.bytes,
+            limit_objects: self.limit_objects,
+            limit_bytes: self.limit_bytes,
+            evictions: self.evictions,
+        }
     }
 
     pub fn gc(&mut self) {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize;
+        let now = now();
+
+        let expired: Vec<String> = self
+            .images
+            .iter()
+            .filter(|(_, (_, ttl, _))| *ttl <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some((value, ..)) = self.images.pop(&key) {
+                self.bytes -= key.len() + value.len();
+            }
+        }
+
+        let expired: Vec<String> = self
+            .texts
+            .iter()
+            .filter(|(_, (_, ttl, _))| *ttl <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some((value, ..)) = self.texts.pop(&key) {
+                self.bytes -= key.len() + value.len();
+            }
+        }
+    }
+
+    // Remove the least recently used entries until both limits are respected
+    fn evict(&mut self) {
+        while self.images.len() + self.texts.len() > self.limit_objects
+            || self.bytes > self.limit_bytes
+        {
+            let image_tick = self.images.peek_lru().map(|(_, (_, _, tick))| *tick);
+            let text_tick = self.texts.peek_lru().map(|(_, (_, _, tick))| *tick);
+
+            let evicted = match (image_tick, text_tick) {
+                (Some(image_tick), Some(text_tick)) if image_tick < text_tick => self
+                    .images
+                    .pop_lru()
+                    .map(|(key, (value, ..))| key.len() + value.len()),
+                (Some(_), None) => self
+                    .images
+                    .pop_lru()
+                    .map(|(key, (value, ..))| key.len() + value.len()),
+                _ => self
+                    .texts
+                    .pop_lru()
+                    .map(|(key, (value, ..))| key.len() + value.len()),
+            };
+
+            match evicted {
+                Some(size) => {
+                    self.bytes -= size;
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn now() -> usize {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+/// `CacheBackend` storing everything in a shared `RamCache`
+pub struct RamBackend {
+    cache: Arc<Mutex<RamCache>>,
+}
+
+impl RamBackend {
+    pub fn new(cache: Arc<Mutex<RamCache>>) -> Self {
+        Self { cache }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RamBackend {
+    async fn get_str(&self, key: &str) -> Result<String, CacheError> {
+        let mut cache = self.cache.lock().await;
+
+        cache.get_str(key).cloned().ok_or(CacheError::NotFound)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+        let mut cache = self.cache.lock().await;
+
+        cache.get_image(key).cloned().ok_or(CacheError::NotFound)
+    }
+
+    async fn set_str(&self, key: &str, value: &str, expiration: usize) -> Result<(), CacheError> {
+        let mut cache = self.cache.lock().await;
+        cache.set_str(key, value.to_string(), expiration);
+
+        Ok(())
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expiration: usize,
+    ) -> Result<(), CacheError> {
+        let mut cache = self.cache.lock().await;
+        cache.set_image(key, value, expiration);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_of_both_maps() {
+        let mut cache = RamCache::new(3, 1024);
+
+        cache.set_image("a", b"a", 60);
+        cache.set_str("b", "b".to_string(), 60);
+        cache.set_image("c", b"c", 60);
+        // "a" becomes the most recently used
+        assert!(cache.get_image("a").is_some());
+
+        cache.set_str("d", "d".to_string(), 60);
+        assert!(cache.get_str("b").is_none());
+        assert!(cache.get_image("c").is_some());
+
+        // "a" is now the least recently used
+        cache.set_image("e", b"e", 60);
+        assert!(cache.get_image("a").is_none());
+        assert!(cache.get_image("c").is_some());
+        assert!(cache.get_str("d").is_some());
+        assert!(cache.get_image("e").is_some());
+    }
+
+    #[test]
+    fn evicts_until_the_bytes_fit() {
+        let mut cache = RamCache::new(100, 20);
+
+        cache.set_image("a", &[0; 8], 60);
+        cache.set_image("b", &[0; 8], 60);
+        assert_eq!(cache.stats().bytes, 18);
+
+        cache.set_str("c", "0".repeat(8), 60);
+        assert!(cache.get_image("a").is_none());
+        assert_eq!(cache.stats().bytes, 18);
+    }
+
+    #[test]
+    fn counts_the_bytes_of_overwritten_keys() {
+        let mut cache = RamCache::new(100, 1024);
+
+        cache.set_image("image", &[0; 10], 60);
+        cache.set_image("image", &[0; 4], 60);
+        assert_eq!(cache.stats().bytes, 5 + 4);
+
+        cache.set_str("text", "0123456789".to_string(), 60);
+        cache.set_str("text", "01".to_string(), 60);
+        assert_eq!(cache.stats().bytes, 5 + 4 + 4 + 2);
+        assert_eq!(cache.stats().images, 1);
+        assert_eq!(cache.stats().texts, 1);
+    }
+
+    #[test]
+    fn gc_removes_the_expired_entries() {
+        let mut cache = RamCache::new(100, 1024);
+
+        cache.set_image("expired", b"content", 0);
+        cache.set_str("expired", "content".to_string(), 0);
+        cache.set_image("fresh", b"content", 60);
+        cache.set_str("fresh", "content".to_string(), 60);
+
+        // Expired entries are never served, even before the gc
+        assert!(cache.get_image("expired").is_none());
+        assert!(cache.get_str("expired").is_none());
+
+        cache.gc();
+        let stats = cache.stats();
+        assert_eq!(stats.images, 1);
+        assert_eq!(stats.texts, 1);
+        assert_eq!(stats.bytes, 2 * ("fresh".len() + "content".len()));
+        // Expired entries are not evictions
+        assert_eq!(stats.evictions, 0);
+    }
+
+    #[test]
+    fn reports_the_evictions() {
+        let mut cache = RamCache::new(2, 1024);
+
+        cache.set_image("a", b"a", 60);
+        cache.set_image("b", b"b", 60);
+        cache.set_str("c", "c".to_string(), 60);
+        cache.set_str("d", "d".to_string(), 60);
 
-        self.images.retain(|_, (_, ttl)| *ttl > now);
-        self.texts.retain(|_, (_, ttl)| *ttl > now);
+        let stats = cache.stats();
+        assert_eq!(stats.images, 0);
+        assert_eq!(stats.texts, 2);
+        assert_eq!(stats.bytes, 4);
+        assert_eq!(stats.limit_objects, 2);
+        assert_eq!(stats.limit_bytes, 1024);
+        assert_eq!(stats.evictions, 2);
     }
 }
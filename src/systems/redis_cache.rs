@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands, FromRedisValue, RedisError, RedisResult};
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::cache::{CacheBackend, CacheError};
+
+const CONNECT_ATTEMPTS: u32 = 5;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// `CacheBackend` storing everything in Redis
+///
+/// The `ConnectionManager` multiplexes every request on one connection (no lock) and
+/// reconnects by itself when the connection is dropped. While Redis is unreachable,
+/// requests fail fast with `CacheError::Unavailable` during an exponential backoff
+/// window instead of waiting for the timeout each time.
+pub struct RedisBackend {
+    connection: ConnectionManager,
+    timeout: Duration,
+    backoff: Mutex<Backoff>,
+}
+
+impl RedisBackend {
+    pub async fn connect(redis_url: &str, timeout: Duration) -> Result<Self, CacheError> {
+        let client = redis::Client::open(redis_url)?;
+
+        let mut attempt = 0;
+        let connection = loop {
+            match ConnectionManager::new(client.clone()).await {
+                Ok(connection) => break connection,
+                Err(err) if attempt + 1 < CONNECT_ATTEMPTS => {
+                    let delay = backoff_delay(attempt);
+                    println!("Redis connection error: {err}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        Ok(Self {
+            connection,
+            timeout,
+            backoff: Mutex::new(Backoff::default()),
+        })
+    }
+
+    /// Get a value along with its remaining time to live in seconds (None if it never expires)
+    pub async fn get_with_ttl<T: FromRedisValue>(
+        &self,
+        key: &str,
+    ) -> Result<(T, Option<usize>), CacheError> {
+        let mut connection = self.connection.clone();
+        let (value, ttl): (Option<T>, i64) = self
+            .run(async move {
+                redis::pipe()
+                    .get(key)
+                    .ttl(key)
+                    .query_async(&mut connection)
+                    .await
+            })
+            .await?;
+
+        match value {
+            Some(value) => Ok((value, usize::try_from(ttl).ok())),
+            None => Err(CacheError::NotFound),
+        }
+    }
+
+    // Run a command with a timeout, keeping track of the connection failures
+    async fn run<T>(&self, command: impl Future<Output = RedisResult<T>>) -> Result<T, CacheError> {
+        if let Some(retry_at) = self.backoff.lock().unwrap().retry_at {
+            if Instant::now() < retry_at {
+                return Err(CacheError::Unavailable);
+            }
+        }
+
+        match tokio::time::timeout(self.timeout, command).await {
+            Ok(Ok(value)) => {
+                *self.backoff.lock().unwrap() = Backoff::default();
+                Ok(value)
+            }
+            Ok(Err(err)) => {
+                if is_connection_error(&err) {
+                    self.failed();
+                }
+                Err(err.into())
+            }
+            Err(_) => {
+                self.failed();
+                Err(CacheError::Timeout)
+            }
+        }
+    }
+
+    fn failed(&self) {
+        let mut backoff = self.backoff.lock().unwrap();
+        let delay = backoff_delay(backoff.failures);
+        backoff.failures += 1;
+        backoff.retry_at = Some(Instant::now() + delay);
+
+        println!("Redis unavailable, next attempt in {delay:?}");
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn get_str(&self, key: &str) -> Result<String, CacheError> {
+        let mut connection = self.connection.clone();
+        let value: Option<String> = self.run(async move { connection.get(key).await }).await?;
+
+        value.ok_or(CacheError::NotFound)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+        let mut connection = self.connection.clone();
+        let value: Option<Vec<u8>> = self.run(async move { connection.get(key).await }).await?;
+
+        value.ok_or(CacheError::NotFound)
+    }
+
+    async fn set_str(&self, key: &str, value: &str, expiration: usize) -> Result<(), CacheError> {
+        let mut connection = self.connection.clone();
+        self.run(async move { connection.set_ex::<_, _, ()>(key, value, expiration).await })
+            .await
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expiration: usize,
+    ) -> Result<(), CacheError> {
+        let mut connection = self.connection.clone();
+        self.run(async move { connection.set_ex::<_, _, ()>(key, value, expiration).await })
+            .await
+    }
+}
+
+fn backoff_delay(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_BACKOFF)
+}
+
+fn is_connection_error(err: &RedisError) -> bool {
+    err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_timeout()
+}
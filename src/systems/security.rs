@@ -1,7 +1,16 @@
+use hmac::{Hmac, Mac};
 use secp256k1::{schnorr::Signature, XOnlyPublicKey, SECP256K1};
+use sha2::Sha256;
 use std::str::FromStr;
 use thiserror::Error;
 
+type HmacSha256 = Hmac<Sha256>;
+
+// Routes that fetch a source URL, they accept a signed URL instead of the pubkey or password access
+fn is_signed_path(path: &str) -> bool {
+    matches!(path, "/image_proxy" | "/image_info" | "/image_placeholder") || path.starts_with("/i/")
+}
+
 #[derive(Debug, Error)]
 pub enum SigError {
     #[error("Signature error: {0}")]
@@ -77,7 +86,13 @@ pub async fn check_access(
     }
 
     // Check if the time is within 5 minutes of the current time
-    let time_of_request = time.parse::<i64>().unwrap();
+    let time_of_request = match time.parse::<i64>() {
+        Ok(time) => time,
+        Err(_) => {
+            println!("Invalid time: {time}");
+            return false;
+        }
+    };
     let current_time = chrono::Utc::now().timestamp();
 
     if (time_of_request - current_time).abs() > 300 {
@@ -97,12 +112,245 @@ pub async fn check_access(
     }
 
     // Set the signature to be used
-    cache
-        .to_owned()
+    if let Err(err) = cache
         .to_owned()
         .set_str(&key, "1", crate::ENV_CONFIG.cache_ttl_signature)
         .await
-        .unwrap();
+    {
+        println!("Cache error: {err}");
+    }
 
     true
 }
+
+/// Check the signature of a signed URL, None if the URL is not signed
+///
+/// The signature is the last query parameter, `signature`, and it is the hex encoded
+/// HMAC-SHA256, keyed with IMAGE_SIGNING_KEY, of the path and the query before it:
+/// `/image_proxy?url=https%3A%2F%2Fexample.com%2Fimage.png&width=800`, or only the path
+/// of the path-style URLs: `/i/width=800/aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.webp`
+///
+/// Signed URLs can be embedded in static pages, so they can be used any number of times
+/// and they never touch the replay cache of the signatures.
+pub fn check_url_signature(path: &str, query: &str) -> Option<bool> {
+    verify_url_signature(crate::ENV_CONFIG.image_signing_key.as_deref(), path, query)
+}
+
+fn verify_url_signature(key: Option<&str>, path: &str, query: &str) -> Option<bool> {
+    if !is_signed_path(path) {
+        return None;
+    }
+
+    let (signed_query, signature) = match query.rsplit_once("&signature=") {
+        Some(parts) => parts,
+        None => ("", query.strip_prefix("signature=")?),
+    };
+
+    let key = match key {
+        Some(key) => key,
+        None => {
+            println!("Signed URL without IMAGE_SIGNING_KEY");
+            return Some(false);
+        }
+    };
+
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => {
+            println!("Invalid URL signature");
+            return Some(false);
+        }
+    };
+
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(path.as_bytes());
+    if !signed_query.is_empty() {
+        mac.update(b"?");
+        mac.update(signed_query.as_bytes());
+    }
+
+    // Constant time comparison
+    if mac.verify_slice(&signature).is_err() {
+        println!("Invalid URL signature");
+        return Some(false);
+    }
+
+    Some(true)
+}
+
+/// Whether an unsigned request to this route must be refused, with IMAGE_REQUIRE_SIGNATURE
+pub fn requires_signature(path: &str) -> bool {
+    path_requires_signature(crate::ENV_CONFIG.image_require_signature, path)
+}
+
+fn path_requires_signature(require_signature: bool, path: &str) -> bool {
+    require_signature && is_signed_path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "secret";
+
+    fn sign(message: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(KEY.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_valid_signatures() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png&width=800";
+        let signature = sign(&format!("/image_proxy?{query}"));
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_proxy",
+                &format!("{query}&signature={signature}")
+            ),
+            Some(true)
+        );
+
+        let path = "/i/width=800/aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.webp";
+        let signature = sign(path);
+        assert_eq!(
+            verify_url_signature(Some(KEY), path, &format!("signature={signature}")),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn signs_every_route_fetching_a_source() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png";
+
+        for path in ["/image_info", "/image_placeholder"] {
+            let signature = sign(&format!("{path}?{query}"));
+            assert_eq!(
+                verify_url_signature(Some(KEY), path, &format!("{query}&signature={signature}")),
+                Some(true)
+            );
+            assert_eq!(
+                verify_url_signature(Some(KEY), path, &format!("{query}&signature=00")),
+                Some(false)
+            );
+            // Unsigned
+            assert_eq!(verify_url_signature(Some(KEY), path, query), None);
+        }
+
+        // A signature of /image_info is not valid for /image_placeholder
+        let signature = sign(&format!("/image_info?{query}"));
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_placeholder",
+                &format!("{query}&signature={signature}")
+            ),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn refuses_tampered_queries() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png&width=800";
+        let signature = sign(&format!("/image_proxy?{query}"));
+
+        let tampered = "url=https%3A%2F%2Fexample.com%2Fimage.png&width=8000";
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_proxy",
+                &format!("{tampered}&signature={signature}")
+            ),
+            Some(false)
+        );
+
+        // Another path with the same query
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/i/_/aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.webp",
+                &format!("{query}&signature={signature}")
+            ),
+            Some(false)
+        );
+
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_proxy",
+                &format!("{query}&signature=not-hex")
+            ),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn signature_must_be_last() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png";
+        let signature = sign(&format!("/image_proxy?{query}"));
+
+        // The parameters after the signature would not be signed
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_proxy",
+                &format!("{query}&signature={signature}&width=800")
+            ),
+            Some(false)
+        );
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/image_proxy",
+                &format!("signature={signature}&{query}")
+            ),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn refuses_signatures_without_key() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png";
+        let signature = sign(&format!("/image_proxy?{query}"));
+
+        assert_eq!(
+            verify_url_signature(
+                None,
+                "/image_proxy",
+                &format!("{query}&signature={signature}")
+            ),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn ignores_unsigned_requests_and_other_routes() {
+        let query = "url=https%3A%2F%2Fexample.com%2Fimage.png";
+        assert_eq!(verify_url_signature(Some(KEY), "/image_proxy", query), None);
+
+        let signature = sign(&format!("/nip05?{query}"));
+        assert_eq!(
+            verify_url_signature(
+                Some(KEY),
+                "/nip05",
+                &format!("{query}&signature={signature}")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn requires_signatures_of_the_image_routes() {
+        assert!(path_requires_signature(true, "/image_proxy"));
+        assert!(path_requires_signature(true, "/image_info"));
+        assert!(path_requires_signature(true, "/image_placeholder"));
+        assert!(path_requires_signature(
+            true,
+            "/i/_/aHR0cHM6Ly9leGFtcGxlLmNvbQ.png"
+        ));
+        assert!(!path_requires_signature(true, "/nip05"));
+        assert!(!path_requires_signature(false, "/image_proxy"));
+    }
+}
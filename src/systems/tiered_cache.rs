@@ -0,0 +1,300 @@
+use async_trait::async_trait;
+
+use super::{
+    cache::{CacheBackend, CacheError},
+    ram_cache::RamBackend,
+    redis_cache::RedisBackend,
+};
+
+/// Cold tier of a `TieredBackend`, which also tells the remaining TTL of its values
+/// in seconds (None if they never expire)
+#[async_trait]
+pub trait ColdBackend: CacheBackend {
+    async fn get_str_with_ttl(&self, key: &str) -> Result<(String, Option<usize>), CacheError>;
+
+    async fn get_bytes_with_ttl(&self, key: &str) -> Result<(Vec<u8>, Option<usize>), CacheError>;
+}
+
+#[async_trait]
+impl ColdBackend for RedisBackend {
+    async fn get_str_with_ttl(&self, key: &str) -> Result<(String, Option<usize>), CacheError> {
+        self.get_with_ttl(key).await
+    }
+
+    async fn get_bytes_with_ttl(&self, key: &str) -> Result<(Vec<u8>, Option<usize>), CacheError> {
+        self.get_with_ttl(key).await
+    }
+}
+
+/// Read-through `CacheBackend` with a RAM hot layer in front of Redis
+///
+/// Redis stays the shared source of truth: writes go to both tiers and a RAM miss is
+/// populated from Redis with the remaining Redis TTL, capped to `hot_ttl` seconds so
+/// that a value updated by another instance is not served stale for too long.
+pub struct TieredBackend<C = RedisBackend> {
+    hot: RamBackend,
+    cold: C,
+    hot_ttl: usize,
+}
+
+impl<C: ColdBackend> TieredBackend<C> {
+    pub fn new(hot: RamBackend, cold: C, hot_ttl: usize) -> Self {
+        Self { hot, cold, hot_ttl }
+    }
+
+    fn hot_expiration(&self, expiration: Option<usize>) -> usize {
+        expiration.map_or(self.hot_ttl, |expiration| expiration.min(self.hot_ttl))
+    }
+}
+
+#[async_trait]
+impl<C: ColdBackend> CacheBackend for TieredBackend<C> {
+    async fn get_str(&self, key: &str) -> Result<String, CacheError> {
+        if let Ok(value) = self.hot.get_str(key).await {
+            return Ok(value);
+        }
+
+        let (value, ttl) = self.cold.get_str_with_ttl(key).await?;
+        self.hot
+            .set_str(key, &value, self.hot_expiration(ttl))
+            .await?;
+
+        Ok(value)
+    }
+
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+        if let Ok(value) = self.hot.get_bytes(key).await {
+            return Ok(value);
+        }
+
+        let (value, ttl) = self.cold.get_bytes_with_ttl(key).await?;
+        self.hot
+            .set_bytes(key, &value, self.hot_expiration(ttl))
+            .await?;
+
+        Ok(value)
+    }
+
+    async fn set_str(&self, key: &str, value: &str, expiration: usize) -> Result<(), CacheError> {
+        self.cold.set_str(key, value, expiration).await?;
+        self.hot
+            .set_str(key, value, self.hot_expiration(Some(expiration)))
+            .await
+    }
+
+    async fn set_bytes(
+        &self,
+        key: &str,
+        value: &[u8],
+        expiration: usize,
+    ) -> Result<(), CacheError> {
+        self.cold.set_bytes(key, value, expiration).await?;
+        self.hot
+            .set_bytes(key, value, self.hot_expiration(Some(expiration)))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::ram_cache::RamCache;
+    use async_lock::Mutex;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // Value and remaining TTL
+    type FakeEntry<T> = (T, Option<usize>);
+
+    // Values of the fake Redis, with the number of reads
+    #[derive(Default)]
+    struct FakeCold {
+        texts: std::sync::Mutex<HashMap<String, FakeEntry<String>>>,
+        bytes: std::sync::Mutex<HashMap<String, FakeEntry<Vec<u8>>>>,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl CacheBackend for FakeCold {
+        async fn get_str(&self, key: &str) -> Result<String, CacheError> {
+            Ok(self.get_str_with_ttl(key).await?.0)
+        }
+
+        async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+            Ok(self.get_bytes_with_ttl(key).await?.0)
+        }
+
+        async fn set_str(
+            &self,
+            key: &str,
+            value: &str,
+            expiration: usize,
+        ) -> Result<(), CacheError> {
+            self.texts
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), (value.to_string(), Some(expiration)));
+            Ok(())
+        }
+
+        async fn set_bytes(
+            &self,
+            key: &str,
+            value: &[u8],
+            expiration: usize,
+        ) -> Result<(), CacheError> {
+            self.bytes
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), (value.to_vec(), Some(expiration)));
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ColdBackend for FakeCold {
+        async fn get_str_with_ttl(&self, key: &str) -> Result<(String, Option<usize>), CacheError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.texts
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or(CacheError::NotFound)
+        }
+
+        async fn get_bytes_with_ttl(
+            &self,
+            key: &str,
+        ) -> Result<(Vec<u8>, Option<usize>), CacheError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.bytes
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or(CacheError::NotFound)
+        }
+    }
+
+    fn tiered(hot_ttl: usize) -> (Arc<Mutex<RamCache>>, TieredBackend<FakeCold>) {
+        let ram_cache = Arc::new(Mutex::new(RamCache::new(100, 1024)));
+        let backend = TieredBackend::new(
+            RamBackend::new(ram_cache.clone()),
+            FakeCold::default(),
+            hot_ttl,
+        );
+
+        (ram_cache, backend)
+    }
+
+    // The clock may tick during the calls, so the expiration is checked against a range
+    fn assert_expires_in(expires: usize, before: usize, after: usize) {
+        assert!(
+            (before..=after).contains(&expires),
+            "expires at {expires}, expected between {before} and {after}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_cold_hit_fills_the_hot_tier() {
+        let (ram_cache, backend) = tiered(60);
+        backend
+            .cold
+            .texts
+            .lock()
+            .unwrap()
+            .insert("text".to_string(), ("value".to_string(), Some(600)));
+        backend
+            .cold
+            .bytes
+            .lock()
+            .unwrap()
+            .insert("bytes".to_string(), (b"value".to_vec(), Some(600)));
+
+        assert_eq!(backend.get_str("text").await.unwrap(), "value");
+        assert_eq!(backend.get_bytes("bytes").await.unwrap(), b"value");
+        assert_eq!(backend.cold.reads.load(Ordering::Relaxed), 2);
+
+        // Served by the hot tier
+        assert_eq!(backend.get_str("text").await.unwrap(), "value");
+        assert_eq!(backend.get_bytes("bytes").await.unwrap(), b"value");
+        assert_eq!(backend.cold.reads.load(Ordering::Relaxed), 2);
+
+        let mut ram_cache = ram_cache.lock().await;
+        assert_eq!(ram_cache.get_str("text").unwrap(), "value");
+        assert_eq!(ram_cache.get_image("bytes").unwrap(), b"value");
+    }
+
+    #[tokio::test]
+    async fn a_cold_miss_is_not_found() {
+        let (_, backend) = tiered(60);
+
+        assert!(matches!(
+            backend.get_str("missing").await,
+            Err(CacheError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_hot_ttl_is_capped() {
+        let (ram_cache, backend) = tiered(60);
+        {
+            let mut texts = backend.cold.texts.lock().unwrap();
+            // Expires in Redis before the hot TTL
+            texts.insert("short".to_string(), ("value".to_string(), Some(10)));
+            // Expires after it
+            texts.insert("long".to_string(), ("value".to_string(), Some(600)));
+            // Never expires
+            texts.insert("persistent".to_string(), ("value".to_string(), None));
+        }
+
+        let before = RamCache::calc_ttl(0);
+        for key in ["short", "long", "persistent"] {
+            backend.get_str(key).await.unwrap();
+        }
+        let after = RamCache::calc_ttl(0);
+
+        let ram_cache = ram_cache.lock().await;
+        let expires = |key: &str| ram_cache.texts.peek(key).unwrap().1;
+        assert_expires_in(expires("short"), before + 10, after + 10);
+        assert_expires_in(expires("long"), before + 60, after + 60);
+        assert_expires_in(expires("persistent"), before + 60, after + 60);
+    }
+
+    #[tokio::test]
+    async fn writes_go_to_both_tiers() {
+        let (ram_cache, backend) = tiered(60);
+
+        let before = RamCache::calc_ttl(0);
+        backend.set_str("short", "value", 10).await.unwrap();
+        backend.set_bytes("long", b"value", 600).await.unwrap();
+        let after = RamCache::calc_ttl(0);
+
+        assert_eq!(
+            backend.cold.texts.lock().unwrap()["short"],
+            ("value".to_string(), Some(10))
+        );
+        assert_eq!(
+            backend.cold.bytes.lock().unwrap()["long"],
+            (b"value".to_vec(), Some(600))
+        );
+
+        let ram_cache = ram_cache.lock().await;
+        assert_expires_in(
+            ram_cache.texts.peek("short").unwrap().1,
+            before + 10,
+            after + 10,
+        );
+        assert_expires_in(
+            ram_cache.images.peek("long").unwrap().1,
+            before + 60,
+            after + 60,
+        );
+    }
+}